@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use tracing::{error, info};
+
+use crate::command;
+use crate::config::get_config;
+use crate::live::{self, LiveEvent};
+use crate::mq::{publish_alarm, Alarm, DeviceData};
+use crate::safety::{self, InfusionLimits};
+use crate::repository::{acknowledge_alarm, fetch_open_alarm, fetch_raised_alarm, insert_alarm, query_open_alarms, resolve_alarm, resolve_open_alarms, AlarmRecord, AlarmType, NewAlarm, Patient};
+
+/// Recorded as `resolved_by` when an alarm clears because its condition went away.
+const SYSTEM: &str = "system";
+
+/// Devices whose SOS button read as pressed in their last frame, so a held button raises only once.
+static SOS_PRESSED: Lazy<Mutex<HashSet<u8>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Consecutive infusing frames without any drip, per device.
+static STALLED_FRAMES: Lazy<Mutex<HashMap<u8, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Devices whose last frame reported a battery level at or below the threshold.
+static LOW_BATTERY: Lazy<Mutex<HashSet<u8>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Devices whose last infusing frame broke the hard limits of the drug library.
+static OUTSIDE_DRUG_LIMITS: Lazy<Mutex<HashSet<u8>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Devices whose last infusing frame reported a temperature outside the limits of their drug.
+static TEMPERATURE_OUT_OF_RANGE: Lazy<Mutex<HashSet<u8>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Since when each device has been dripping outside the tolerance band, and whether that was reported.
+static DRIFTING: Lazy<Mutex<HashMap<u8, Drift>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Drift {
+    since: Instant,
+    raised: bool,
+}
+
+/// Seeds the per-device condition state from the alarms left open by the previous run, so that
+/// they still clear once the first healthy frame arrives.
+pub async fn restore() -> Result<usize, sqlx::Error> {
+    let alarms = query_open_alarms(None).await?;
+    let occlusion_frames = get_config().alarm.occlusion_frames;
+    let mut restored = 0;
+
+    for alarm in alarms.iter() {
+        let Some(device_id) = alarm.device_id else {
+            continue;
+        };
+
+        let restored_alarm = match AlarmType::from_name(&alarm.alarm_type) {
+            Some(AlarmType::Occlusion) => STALLED_FRAMES.lock().unwrap().insert(device_id, occlusion_frames).is_none(),
+            Some(AlarmType::LowBattery) => LOW_BATTERY.lock().unwrap().insert(device_id),
+            Some(AlarmType::DrugLimitExceeded) => OUTSIDE_DRUG_LIMITS.lock().unwrap().insert(device_id),
+            Some(AlarmType::TemperatureOutOfRange) => TEMPERATURE_OUT_OF_RANGE.lock().unwrap().insert(device_id),
+            Some(AlarmType::DripRateDeviation) => DRIFTING.lock().unwrap()
+                .insert(device_id, Drift { since: Instant::now(), raised: true })
+                .is_none(),
+            _ => false,
+        };
+
+        if restored_alarm {
+            restored += 1;
+        }
+    }
+
+    Ok(restored)
+}
+
+/// Raises an alarm unless one of the same type is still open for the device.
+pub async fn raise(alarm_type: AlarmType, device_id: u8, patient: Option<&Patient>, message: Option<String>) -> Option<AlarmRecord> {
+    match fetch_open_alarm(alarm_type, device_id).await {
+        Ok(Some(_)) => return None,
+        Ok(None) => {},
+        Err(e) => {
+            error!("fetch open {} alarm failed: {}", alarm_type.as_str(), e);
+            return None;
+        }
+    }
+
+    create(alarm_type, device_id, patient, message).await
+}
+
+/// Resolves the open alarms of the given type for the device once their condition has cleared.
+pub async fn clear(alarm_type: AlarmType, device_id: u8) {
+    match resolve_open_alarms(alarm_type, device_id, SYSTEM).await {
+        Ok(alarms) => {
+            for alarm in alarms {
+                info!("{} alarm {} of device {} cleared", alarm.alarm_type, alarm.id, device_id);
+                notify_updated(&alarm).await;
+            }
+        }
+        Err(e) => error!("resolve {} alarms failed: {}", alarm_type.as_str(), e),
+    }
+}
+
+async fn create(alarm_type: AlarmType, device_id: u8, patient: Option<&Patient>, message: Option<String>) -> Option<AlarmRecord> {
+    let alarm = NewAlarm {
+        alarm_type,
+        device_id: Some(device_id),
+        patient_no: patient.map(|p| p.patient_no.clone()),
+        patient_name: patient.map(|p| p.name.clone()),
+        bed_no: patient.map(|p| p.bed_no.clone()),
+        message,
+    };
+
+    match insert_alarm(alarm).await {
+        Ok(record) => {
+            info!("{} alarm {} raised by device {}", record.alarm_type, record.id, device_id);
+            live::broadcast(LiveEvent::AlarmRaised(record.clone()), record.patient_no.clone(), record.bed_no.clone());
+            publish_alarm(Alarm::new(record.clone())).await;
+            Some(record)
+        }
+        Err(e) => {
+            error!("insert {} alarm failed: {}", alarm_type.as_str(), e);
+            None
+        }
+    }
+}
+
+/// Runs every per-frame alarm check against a `device_data` frame.
+pub async fn evaluate(device_data: &DeviceData, patient: Option<&Patient>) {
+    handle_sos(device_data, patient).await;
+    handle_occlusion(device_data, patient).await;
+    handle_low_battery(device_data, patient).await;
+
+    let limits = match patient {
+        Some(patient) if device_data.is_infusing() => match safety::limits_for(device_data.device_id, Some(patient)).await {
+            Ok(limits) => Some(limits),
+            Err(e) => {
+                error!("fetch infusion limits of device {} failed: {}", device_data.device_id, e);
+                return;
+            }
+        },
+        _ => None,
+    };
+    handle_drug_limits(device_data, patient, limits.as_ref()).await;
+    handle_drift(device_data, patient, limits.as_ref()).await;
+    handle_temperature(device_data, patient, limits.as_ref()).await;
+}
+
+async fn handle_sos(device_data: &DeviceData, patient: Option<&Patient>) {
+    let newly_pressed = {
+        let mut pressed = SOS_PRESSED.lock().unwrap();
+        if device_data.is_sos() {
+            pressed.insert(device_data.device_id)
+        } else {
+            pressed.remove(&device_data.device_id);
+            false
+        }
+    };
+
+    if !newly_pressed {
+        return;
+    }
+
+    // Every press is a new call for a nurse, unless one is still unanswered, e.g. across a service restart.
+    match fetch_raised_alarm(AlarmType::Sos, device_data.device_id).await {
+        Ok(Some(_)) => return,
+        Ok(None) => {},
+        Err(e) => {
+            error!("fetch raised sos alarm failed: {}", e);
+            return;
+        }
+    }
+
+    create(AlarmType::Sos, device_data.device_id, patient, None).await;
+}
+
+async fn handle_occlusion(device_data: &DeviceData, patient: Option<&Patient>) {
+    let (frames, resumed) = {
+        let mut stalled = STALLED_FRAMES.lock().unwrap();
+        if device_data.is_infusing() && device_data.drip_value == 0 {
+            let frames = stalled.entry(device_data.device_id).or_insert(0);
+            *frames += 1;
+            (*frames, false)
+        } else {
+            (0, stalled.remove(&device_data.device_id).is_some())
+        }
+    };
+
+    if frames >= get_config().alarm.occlusion_frames {
+        let message = format!("no drip detected in {} consecutive frames", frames);
+        raise(AlarmType::Occlusion, device_data.device_id, patient, Some(message)).await;
+    } else if resumed {
+        clear(AlarmType::Occlusion, device_data.device_id).await;
+    }
+}
+
+async fn handle_low_battery(device_data: &DeviceData, patient: Option<&Patient>) {
+    let Some(threshold) = get_config().alarm.low_battery_threshold else {
+        return;
+    };
+
+    let is_low = device_data.power_state <= threshold;
+    let changed = {
+        let mut low = LOW_BATTERY.lock().unwrap();
+        if is_low {
+            low.insert(device_data.device_id)
+        } else {
+            low.remove(&device_data.device_id)
+        }
+    };
+
+    if !changed {
+        return;
+    }
+
+    if is_low {
+        let message = format!("battery level {}", device_data.power_state);
+        raise(AlarmType::LowBattery, device_data.device_id, patient, Some(message)).await;
+    } else {
+        clear(AlarmType::LowBattery, device_data.device_id).await;
+    }
+}
+
+/// `limits` is only given while the device is infusing for a bound patient.
+async fn handle_drug_limits(device_data: &DeviceData, patient: Option<&Patient>, limits: Option<&InfusionLimits>) {
+    let violation = limits.and_then(|limits| drug_limit_violation(device_data, limits));
+
+    let changed = {
+        let mut outside = OUTSIDE_DRUG_LIMITS.lock().unwrap();
+        if violation.is_some() {
+            outside.insert(device_data.device_id)
+        } else {
+            outside.remove(&device_data.device_id)
+        }
+    };
+
+    if !changed {
+        return;
+    }
+
+    match violation {
+        Some(message) => {
+            raise(AlarmType::DrugLimitExceeded, device_data.device_id, patient, Some(message)).await;
+        }
+        None => clear(AlarmType::DrugLimitExceeded, device_data.device_id).await,
+    }
+}
+
+/// A stalled drip is left to the occlusion check.
+fn drug_limit_violation(device_data: &DeviceData, limits: &InfusionLimits) -> Option<String> {
+    if device_data.drip_value != 0 && !limits.within_hard_limits(device_data.drip_value) {
+        return Some(format!("drip rate {} outside {}-{}", device_data.drip_value, limits.hard_min, limits.hard_max));
+    }
+
+    if !limits.within_max_volume(device_data.preset_amount) {
+        return Some(format!("preset amount {} above maximum volume {}", device_data.preset_amount, limits.max_volume.unwrap_or_default()));
+    }
+
+    None
+}
+
+/// Compares the actual rate with the rate the device was last confirmed at, or with the
+/// prescription of the current drug when nobody changed it. A stalled drip is left to the
+/// occlusion check.
+async fn handle_drift(device_data: &DeviceData, patient: Option<&Patient>, limits: Option<&InfusionLimits>) {
+    let alarm_config = &get_config().alarm;
+
+    let target = command::drip_rate_target(device_data.device_id).map(u16::from);
+    let expected = limits.and_then(|l| target.or(l.prescribed_rate)).filter(|_| device_data.drip_value != 0);
+    let outside = expected.is_some_and(|expected| drifts(device_data.drip_value, expected, alarm_config.drift_tolerance_ratio));
+
+    let action = track_drift(
+        &mut DRIFTING.lock().unwrap(),
+        device_data.device_id,
+        outside,
+        Instant::now(),
+        Duration::from_secs(alarm_config.drift_duration_secs),
+    );
+
+    match action {
+        DriftAction::Raise(elapsed) => {
+            let message = format!("drip rate {} deviates from expected {} for {}s", device_data.drip_value, expected.unwrap_or_default(), elapsed.as_secs());
+            raise(AlarmType::DripRateDeviation, device_data.device_id, patient, Some(message)).await;
+        }
+        DriftAction::Clear => clear(AlarmType::DripRateDeviation, device_data.device_id).await,
+        DriftAction::None => {}
+    }
+}
+
+fn drifts(drip_value: u8, expected: u16, tolerance_ratio: f64) -> bool {
+    (drip_value as f64 - expected as f64).abs() > expected as f64 * tolerance_ratio
+}
+
+#[derive(Debug, PartialEq)]
+enum DriftAction { Raise(Duration), Clear, None }
+
+/// Raises once the device has been outside the band for `duration`, clears when it is back.
+fn track_drift(drifting: &mut HashMap<u8, Drift>, device_id: u8, outside: bool, now: Instant, duration: Duration) -> DriftAction {
+    if outside {
+        let drift = drifting.entry(device_id).or_insert(Drift { since: now, raised: false });
+        let elapsed = now.duration_since(drift.since);
+        if !drift.raised && elapsed >= duration {
+            drift.raised = true;
+            DriftAction::Raise(elapsed)
+        } else {
+            DriftAction::None
+        }
+    } else {
+        match drifting.remove(&device_id) {
+            Some(drift) if drift.raised => DriftAction::Clear,
+            _ => DriftAction::None,
+        }
+    }
+}
+
+async fn handle_temperature(device_data: &DeviceData, patient: Option<&Patient>, limits: Option<&InfusionLimits>) {
+    let violation = limits.and_then(|limits| {
+        if limits.min_temperature.is_some_and(|min| device_data.tem_value < min) {
+            Some(format!("under temperature: {} below {}", device_data.tem_value, limits.min_temperature.unwrap_or_default()))
+        } else if limits.max_temperature.is_some_and(|max| device_data.tem_value > max) {
+            Some(format!("over temperature: {} above {}", device_data.tem_value, limits.max_temperature.unwrap_or_default()))
+        } else {
+            None
+        }
+    });
+
+    let changed = {
+        let mut out_of_range = TEMPERATURE_OUT_OF_RANGE.lock().unwrap();
+        if violation.is_some() {
+            out_of_range.insert(device_data.device_id)
+        } else {
+            out_of_range.remove(&device_data.device_id)
+        }
+    };
+
+    if !changed {
+        return;
+    }
+
+    match violation {
+        Some(message) => {
+            raise(AlarmType::TemperatureOutOfRange, device_data.device_id, patient, Some(message)).await;
+        }
+        None => clear(AlarmType::TemperatureOutOfRange, device_data.device_id).await,
+    }
+}
+
+pub async fn acknowledge(id: i64, nurse: String) -> Result<Option<AlarmRecord>, sqlx::Error> {
+    let alarm = acknowledge_alarm(id, nurse).await?;
+    if let Some(alarm) = &alarm {
+        notify_updated(alarm).await;
+    }
+    Ok(alarm)
+}
+
+pub async fn resolve(id: i64, nurse: Option<String>) -> Result<Option<AlarmRecord>, sqlx::Error> {
+    let alarm = resolve_alarm(id, nurse).await?;
+    if let Some(alarm) = &alarm {
+        notify_updated(alarm).await;
+    }
+    Ok(alarm)
+}
+
+async fn notify_updated(alarm: &AlarmRecord) {
+    live::broadcast(LiveEvent::AlarmUpdated(alarm.clone()), alarm.patient_no.clone(), alarm.bed_no.clone());
+    publish_alarm(Alarm::new(alarm.clone())).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DURATION: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn drift_band_is_the_tolerance_around_the_expected_rate() {
+        assert!(!drifts(40, 40, 0.1));
+        assert!(!drifts(44, 40, 0.1));
+        assert!(!drifts(36, 40, 0.1));
+        assert!(drifts(45, 40, 0.1));
+        assert!(drifts(35, 40, 0.1));
+    }
+
+    #[test]
+    fn raises_once_after_drifting_for_the_duration() {
+        let mut drifting = HashMap::new();
+        let start = Instant::now();
+
+        assert_eq!(track_drift(&mut drifting, 5, true, start, DURATION), DriftAction::None);
+        assert_eq!(track_drift(&mut drifting, 5, true, start + Duration::from_secs(59), DURATION), DriftAction::None);
+        assert_eq!(track_drift(&mut drifting, 5, true, start + DURATION, DURATION), DriftAction::Raise(DURATION));
+        assert_eq!(track_drift(&mut drifting, 5, true, start + Duration::from_secs(120), DURATION), DriftAction::None);
+    }
+
+    #[test]
+    fn clears_only_a_raised_drift() {
+        let mut drifting = HashMap::new();
+        let start = Instant::now();
+
+        track_drift(&mut drifting, 5, true, start, DURATION);
+        assert_eq!(track_drift(&mut drifting, 5, false, start + Duration::from_secs(30), DURATION), DriftAction::None);
+
+        // Back in the band resets the timer.
+        assert_eq!(track_drift(&mut drifting, 5, true, start + Duration::from_secs(40), DURATION), DriftAction::None);
+        assert_eq!(track_drift(&mut drifting, 5, true, start + Duration::from_secs(70), DURATION), DriftAction::None);
+        assert!(matches!(track_drift(&mut drifting, 5, true, start + Duration::from_secs(100), DURATION), DriftAction::Raise(_)));
+        assert_eq!(track_drift(&mut drifting, 5, false, start + Duration::from_secs(110), DURATION), DriftAction::Clear);
+        assert!(drifting.is_empty());
+    }
+
+    #[test]
+    fn tracks_devices_separately() {
+        let mut drifting = HashMap::new();
+        let start = Instant::now();
+
+        track_drift(&mut drifting, 5, true, start, DURATION);
+        assert_eq!(track_drift(&mut drifting, 6, true, start + DURATION, DURATION), DriftAction::None);
+        assert_eq!(track_drift(&mut drifting, 5, true, start + DURATION, DURATION), DriftAction::Raise(DURATION));
+    }
+}
@@ -1,9 +1,20 @@
 use axum::extract::Query;
+use chrono::{Local, NaiveDateTime};
 use axum::response::Response;
 use serde::{Deserialize, Serialize};
-use crate::mq::{publish_drip_rate, publish_modify_preset_amount, publish_start_or_stop_drip_cmd, publish_turn_off_device_cmd, DeviceStatus, ModifyPresetAmountCmd, SetUpDripRateCmd, StartOrStopDripCmd, TurnOffDeviceCmd};
-use crate::repository::{fetch_all_patient_page, query_bed, query_device, query_infusions, PatientDetail};
+use crate::alarm;
+use crate::binding::{self, PatientKey};
+use crate::drug_library;
+use crate::drug_plan;
+use crate::safety::{self, SafetyError};
+use crate::infusion;
+use crate::command::{dispatch, CommandReceipt};
+use crate::mq::get_mq_status;
+use crate::protocol::DeviceCommand;
+use crate::repository::{fetch_all_patient_page, fetch_command_by_id, query_alarms, query_open_alarms, query_active_infusions, query_bed, query_binding_attempts, query_drip_rate_overrides, query_drug_library,
+    delete_drug_library_entry_by_id, DrugLibraryEntry, query_device, query_infusion_history, query_patient_transitions, query_telemetry, AlarmQuery, AlarmType, PatientDetail, TelemetryQuery};
 use crate::{db::get_db, repository::query_patient};
+use crate::config::get_config;
 use crate::http_client::HttpClient;
 use axum::{
     response::IntoResponse,
@@ -38,6 +49,41 @@ pub struct PaginationParams {
 pub struct ModifyDripRate {
     pub device_id: u8,
     pub drip_rate: u8,
+    pub override_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InfusionLimitsParam {
+    pub device_id: u8,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfirmNextDrug {
+    pub patient_no: String,
+    pub nurse: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DrugLibraryParam {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteDrugLibraryEntry {
+    pub id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DripRateOverrideParam {
+    pub page_num: u16,
+    pub page_size: u16,
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,6 +101,99 @@ pub struct PatientDetailParam {
     pub name: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InfusionHistoryParam {
+    pub page_num: u16,
+    pub page_size: u16,
+    pub patient_no: Option<String>,
+    pub device_id: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BindingAttemptParam {
+    pub page_num: u16,
+    pub page_size: u16,
+    pub device_id: Option<u8>,
+    pub success: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnbindDevice {
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
+    pub bed_no: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BindDevice {
+    pub device_id: u8,
+    pub patient_no: Option<String>,
+    pub bed_no: Option<String>,
+}
+
+fn patient_key(patient_no: Option<String>, bed_no: Option<String>) -> Option<PatientKey> {
+    patient_no.map(PatientKey::PatientNo).or(bed_no.map(PatientKey::BedNo))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatientTransitionsParam {
+    pub patient_no: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TelemetryParam {
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub bucket_secs: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandStatusParam {
+    pub command_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlarmListParam {
+    pub alarm_type: Option<String>,
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
+    pub state: Option<u8>,
+    pub active_only: Option<bool>,
+    pub page_num: Option<u16>,
+    pub page_size: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcknowledgeAlarm {
+    pub alarm_id: i64,
+    pub nurse: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolveAlarm {
+    pub alarm_id: i64,
+    pub nurse: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrateDropsPerMl {
+    pub device_id: u8,
+    pub drops_per_ml: u16,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StartOrStopDrip {
@@ -68,8 +207,15 @@ pub struct ModifyPresetAmount {
     pub preset_amount: u16,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetTemGear {
+    pub device_id: u8,
+    pub tem_gear: u8,
+}
+
 pub async fn sync_remote_patient_data() -> impl IntoResponse {
-    let http_client = HttpClient::new("http://172.16.80.253:1024/".to_string());
+    let http_client = HttpClient::new(get_config().his.base_url.clone());
     
     match http_client.fetch_and_store_patients().await {
         Ok(_) => {
@@ -98,7 +244,7 @@ pub async fn fetch_patients() -> impl IntoResponse {
 }
 
 pub async fn sync_remote_device_data() -> impl IntoResponse {
-    let http_client = HttpClient::new("http://172.16.80.253:1024/".to_string());
+    let http_client = HttpClient::new(get_config().his.base_url.clone());
     match http_client.fetch_and_store_devices().await {
         Ok(_) => {
             info!("success to fetch and store devices data");
@@ -121,7 +267,7 @@ pub async fn fetch_devices() -> impl IntoResponse {
 }
 
 pub async fn sync_remote_bed_data() -> impl IntoResponse {
-    let http_client = HttpClient::new("http://172.16.80.253:1024/".to_string());
+    let http_client = HttpClient::new(get_config().his.base_url.clone());
     match http_client.fetch_and_store_beds().await {
         Ok(_) => {
             info!("success to fetch and store beds data");
@@ -143,43 +289,254 @@ pub async fn fetch_beds() -> impl IntoResponse {
     }
 }
 
+async fn send_device_command(cmd: DeviceCommand) -> Response {
+    command_response(dispatch(cmd).await)
+}
+
+fn command_response(result: Result<CommandReceipt, String>) -> Response {
+    match result {
+        Ok(receipt) => match receipt.publish_error.clone() {
+            None => (StatusCode::OK, Json(ApiResponse::<CommandReceipt>::new(0, "pending".to_string(), Some(receipt)))).into_response(),
+            Some(e) => (StatusCode::OK, Json(ApiResponse::<CommandReceipt>::new(1, e, Some(receipt)))).into_response(),
+        },
+        Err(e) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, e, None))).into_response(),
+    }
+}
+
 pub async fn modify_drip_rate(Json(modify_drip_rate): Json<ModifyDripRate>) -> impl IntoResponse {
-    publish_drip_rate(SetUpDripRateCmd::new(modify_drip_rate.device_id, modify_drip_rate.drip_rate)).await;
+    safety_response(safety::set_drip_rate(modify_drip_rate.device_id, modify_drip_rate.drip_rate, modify_drip_rate.override_reason).await)
+}
 
-    (StatusCode::OK, Json(ApiResponse::<()>::new(0, "success".to_string(), None))).into_response()
+fn safety_response(result: Result<CommandReceipt, SafetyError>) -> Response {
+    match result {
+        Ok(receipt) => command_response(Ok(receipt)),
+        Err(SafetyError::Command(e)) => command_response(Err(e)),
+        Err(e) => (StatusCode::OK, Json(ApiResponse::new(1, e.to_string(), e.limits().cloned()))).into_response(),
+    }
 }
 
-pub async fn turn_off_device(Json(turn_off_device): Json<TurnOffDevice>) -> impl IntoResponse {
-    publish_turn_off_device_cmd(TurnOffDeviceCmd::new(turn_off_device.device_id)).await;
+pub async fn infusion_limits(Query(param): Query<InfusionLimitsParam>) -> impl IntoResponse {
+    match safety::infusion_limits(param.device_id).await {
+        Ok(limits) => (StatusCode::OK, Json(limits)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
 
-    (StatusCode::OK, Json(ApiResponse::<()>::new(0, "success".to_string(), None))).into_response()
+pub async fn drip_rate_overrides(Query(param): Query<DripRateOverrideParam>) -> impl IntoResponse {
+    match query_drip_rate_overrides(param.page_num, param.page_size, param.device_id, param.patient_no).await {
+        Ok(overrides) => (StatusCode::OK, Json(overrides)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
 }
 
-pub async fn start_drip(Json(start_drip): Json<StartOrStopDrip>) -> impl IntoResponse {
-    publish_start_or_stop_drip_cmd(StartOrStopDripCmd::new(start_drip.device_id, -22)).await;
+pub async fn turn_off_device(Json(turn_off_device): Json<TurnOffDevice>) -> impl IntoResponse {
+    send_device_command(DeviceCommand::TurnOff { device_id: turn_off_device.device_id }).await
+}
 
-    (StatusCode::OK, Json(ApiResponse::<()>::new(0, "success".to_string(), None))).into_response()
+pub async fn start_drip(Json(start_drip): Json<StartOrStopDrip>) -> impl IntoResponse {
+    send_device_command(DeviceCommand::StartDrip { device_id: start_drip.device_id }).await
 }
 
 pub async fn stop_drip(Json(stop_drip): Json<StartOrStopDrip>) -> impl IntoResponse {
-    publish_start_or_stop_drip_cmd(StartOrStopDripCmd::new(stop_drip.device_id, -17)).await;
-
-    (StatusCode::OK, Json(ApiResponse::<()>::new(0, "success".to_string(), None))).into_response()
+    send_device_command(DeviceCommand::StopDrip { device_id: stop_drip.device_id }).await
 }
 
 pub async fn modify_preset_amount(Json(modify_preset_amount): Json<ModifyPresetAmount>) -> impl IntoResponse {
-    publish_modify_preset_amount(ModifyPresetAmountCmd::new(modify_preset_amount.device_id, modify_preset_amount.preset_amount)).await;
+    safety_response(safety::set_preset_amount(modify_preset_amount.device_id, modify_preset_amount.preset_amount).await)
+}
+
+pub async fn confirm_next_drug(Json(param): Json<ConfirmNextDrug>) -> impl IntoResponse {
+    match drug_plan::confirm_next(param.patient_no, param.nurse).await {
+        Ok(drug) => (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(drug)))).into_response(),
+        Err(e) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, e.to_string(), None))).into_response(),
+    }
+}
+
+pub async fn set_tem_gear(Json(set_tem_gear): Json<SetTemGear>) -> impl IntoResponse {
+    safety_response(safety::set_tem_gear(set_tem_gear.device_id, set_tem_gear.tem_gear).await)
+}
+
+pub async fn drug_library(Query(param): Query<DrugLibraryParam>) -> impl IntoResponse {
+    match query_drug_library(param.name).await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+pub async fn save_drug_library_entry(Json(entry): Json<DrugLibraryEntry>) -> impl IntoResponse {
+    match drug_library::save(entry).await {
+        Ok(()) => (StatusCode::OK, Json(ApiResponse::<()>::new(0, "success".to_string(), None))).into_response(),
+        Err(e) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, e.to_string(), None))).into_response(),
+    }
+}
+
+pub async fn delete_drug_library_entry(Json(param): Json<DeleteDrugLibraryEntry>) -> impl IntoResponse {
+    match delete_drug_library_entry_by_id(param.id).await {
+        Ok(true) => (StatusCode::OK, Json(ApiResponse::<()>::new(0, "success".to_string(), None))).into_response(),
+        Ok(false) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, "drug not found".to_string(), None))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+/// Takes the library as a YAML list in the request body, see README.
+pub async fn import_drug_library(body: String) -> impl IntoResponse {
+    match drug_library::import_yaml(&body).await {
+        Ok(count) => (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(count)))).into_response(),
+        Err(e) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, e.to_string(), None))).into_response(),
+    }
+}
+
+pub async fn command_status(Query(param): Query<CommandStatusParam>) -> impl IntoResponse {
+    match fetch_command_by_id(param.command_id).await {
+        Ok(Some(command)) => (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(command)))).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(ApiResponse::<()>::new(1, "command not found".to_string(), None))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+pub async fn mq_status() -> impl IntoResponse {
+    (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(get_mq_status())))).into_response()
+}
+
+pub async fn sos_alarms() -> impl IntoResponse {
+    match query_open_alarms(Some(AlarmType::Sos.as_str().to_string())).await {
+        Ok(alarms) => (StatusCode::OK, Json(alarms)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+/// Active alarms by default, highest severity first; `activeOnly=false` includes resolved ones.
+pub async fn alarms(Query(param): Query<AlarmListParam>) -> impl IntoResponse {
+    let filter = AlarmQuery {
+        alarm_type: param.alarm_type,
+        device_id: param.device_id,
+        patient_no: param.patient_no,
+        state: param.state,
+        active_only: param.active_only.unwrap_or(true),
+        page: param.page_num.zip(param.page_size),
+    };
+
+    match query_alarms(filter).await {
+        Ok(alarms) => (StatusCode::OK, Json(alarms)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
 
-    (StatusCode::OK, Json(ApiResponse::<()>::new(0, "success".to_string(), None))).into_response()
+pub async fn acknowledge_alarm(Json(param): Json<AcknowledgeAlarm>) -> impl IntoResponse {
+    match alarm::acknowledge(param.alarm_id, param.nurse).await {
+        Ok(Some(alarm)) => (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(alarm)))).into_response(),
+        Ok(None) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, "alarm not found or already acknowledged".to_string(), None))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
 }
 
+pub async fn resolve_alarm(Json(param): Json<ResolveAlarm>) -> impl IntoResponse {
+    match alarm::resolve(param.alarm_id, param.nurse).await {
+        Ok(Some(alarm)) => (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(alarm)))).into_response(),
+        Ok(None) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, "alarm not found or already resolved".to_string(), None))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
 
 pub async fn patient_detail(Query(patient_detail): Query<PatientDetailParam>) -> (StatusCode, Json<Vec<PatientDetail>>) {
     match fetch_all_patient_page(patient_detail.page_num, patient_detail.page_size, patient_detail.status, patient_detail.name).await {
-        Ok(patients) => (StatusCode::OK, Json(patients)),
+        Ok(mut patients) => {
+            for patient in patients.iter_mut() {
+                patient.estimate = patient.device_id.and_then(|device_id| u8::try_from(device_id).ok()).and_then(infusion::estimate);
+            }
+            (StatusCode::OK, Json(patients))
+        },
         Err(e) => {
             (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new()))
         }
     }
 }
 
+pub async fn bind_device(Json(param): Json<BindDevice>) -> impl IntoResponse {
+    let Some(key) = patient_key(param.patient_no, param.bed_no) else {
+        return (StatusCode::BAD_REQUEST, Json(ApiResponse::<()>::new(1, "patientNo or bedNo is required".to_string(), None))).into_response();
+    };
+
+    let result = binding::bind_by_request(param.device_id, key).await;
+    if result.success {
+        (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(result)))).into_response()
+    } else {
+        (StatusCode::OK, Json(ApiResponse::new(1, result.message.clone().unwrap_or_default(), Some(result)))).into_response()
+    }
+}
+
+pub async fn unbind_device(Json(param): Json<UnbindDevice>) -> impl IntoResponse {
+    let key = patient_key(param.patient_no, param.bed_no);
+    if param.device_id.is_none() && key.is_none() {
+        return (StatusCode::BAD_REQUEST, Json(ApiResponse::<()>::new(1, "deviceId, patientNo or bedNo is required".to_string(), None))).into_response();
+    }
+
+    match binding::unbind_by_request(param.device_id, key).await {
+        Ok(Some(patient)) => (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), Some(patient)))).into_response(),
+        Ok(None) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, "device is not bound".to_string(), None))).into_response(),
+        Err(e) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, e.to_string(), None))).into_response(),
+    }
+}
+
+pub async fn binding_attempts(Query(param): Query<BindingAttemptParam>) -> impl IntoResponse {
+    match query_binding_attempts(param.page_num, param.page_size, param.device_id, param.success).await {
+        Ok(attempts) => (StatusCode::OK, Json(attempts)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+pub async fn patient_transitions(Query(param): Query<PatientTransitionsParam>) -> impl IntoResponse {
+    match query_patient_transitions(param.patient_no).await {
+        Ok(transitions) => (StatusCode::OK, Json(transitions)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+/// Drip rate and volume curve of a device or patient, the last hour unless a range is given.
+pub async fn telemetry_history(Query(param): Query<TelemetryParam>) -> impl IntoResponse {
+    if param.device_id.is_none() && param.patient_no.is_none() {
+        return (StatusCode::BAD_REQUEST, Json(ApiResponse::<()>::new(1, "deviceId or patientNo is required".to_string(), None))).into_response();
+    }
+
+    let to = param.to.unwrap_or_else(|| Local::now().naive_local());
+    let from = param.from.unwrap_or_else(|| to - chrono::Duration::hours(1));
+
+    let filter = TelemetryQuery {
+        device_id: param.device_id,
+        patient_no: param.patient_no,
+        from,
+        to,
+        bucket_secs: param.bucket_secs,
+    };
+
+    match query_telemetry(filter).await {
+        Ok(points) => (StatusCode::OK, Json(points)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+pub async fn calibrate_drops_per_ml(Json(param): Json<CalibrateDropsPerMl>) -> impl IntoResponse {
+    if param.drops_per_ml == 0 {
+        return (StatusCode::BAD_REQUEST, Json(ApiResponse::<()>::new(1, "dropsPerMl must be positive".to_string(), None))).into_response();
+    }
+
+    match infusion::calibrate(param.device_id, param.drops_per_ml).await {
+        Ok(true) => (StatusCode::OK, Json(ApiResponse::new(0, "success".to_string(), infusion::estimate(param.device_id)))).into_response(),
+        Ok(false) => (StatusCode::OK, Json(ApiResponse::<()>::new(1, "device has no running infusion".to_string(), None))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+pub async fn active_infusions() -> impl IntoResponse {
+    match query_active_infusions().await {
+        Ok(infusions) => (StatusCode::OK, Json(infusions)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
+
+pub async fn infusion_history(Query(param): Query<InfusionHistoryParam>) -> impl IntoResponse {
+    match query_infusion_history(param.page_num, param.page_size, param.patient_no, param.device_id).await {
+        Ok(infusions) => (StatusCode::OK, Json(infusions)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    }
+}
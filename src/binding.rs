@@ -0,0 +1,369 @@
+use std::fmt;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::alarm;
+use crate::command;
+use crate::drug_plan;
+use crate::infusion;
+use crate::mq::publish_binding_result;
+use crate::patient_state::{self, PatientState, TransitionCause, TransitionError};
+use crate::repository::{
+    apply_unbinding, fetch_active_infusion_by_device_id, fetch_bed_by_bed_mac, fetch_device_by_device_id,
+    fetch_device_by_device_mac, fetch_patient_by_bed_no, fetch_patient_by_device_id, fetch_patient_by_patient_no, insert_binding_attempt,
+    update_patient_device_id, AlarmType, Device, InfusionFinalState, NewBindingAttempt, Patient,
+};
+
+/// 设备状态：开机
+const DEVICE_ON: u8 = 1;
+
+#[derive(Debug)]
+pub enum BindingError {
+    DeviceNotFound,
+    DeviceNotPoweredOn { device_id: u8 },
+    BedNotFound,
+    PatientNotFound { bed_no: String },
+    UnknownPatient { patient_no: String },
+    PatientNotBound { patient_no: String },
+    InvalidDeviceId { patient_no: String, device_id: u16 },
+    PatientAlreadyBound { patient_no: String, device_id: u16 },
+    DeviceInUse { device_id: u8, patient_no: String },
+    State(TransitionError),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingError::DeviceNotFound => write!(f, "device not found"),
+            BindingError::DeviceNotPoweredOn { device_id } => write!(f, "device {} is not powered on", device_id),
+            BindingError::BedNotFound => write!(f, "bed not found"),
+            BindingError::PatientNotFound { bed_no } => write!(f, "no patient on bed {}", bed_no),
+            BindingError::UnknownPatient { patient_no } => write!(f, "patient {} not found", patient_no),
+            BindingError::PatientNotBound { patient_no } => write!(f, "patient {} has no device bound", patient_no),
+            BindingError::InvalidDeviceId { patient_no, device_id } => write!(f, "patient {} is bound to invalid device id {}", patient_no, device_id),
+            BindingError::PatientAlreadyBound { patient_no, device_id } => write!(f, "patient {} is already bound to device {}", patient_no, device_id),
+            BindingError::DeviceInUse { device_id, patient_no } => write!(f, "device {} is infusing patient {}", device_id, patient_no),
+            BindingError::State(e) => write!(f, "{}", e),
+            BindingError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BindingError {}
+
+impl BindingError {
+    /// Stable reason code sent back to the bedside hardware.
+    pub fn code(&self) -> &'static str {
+        match self {
+            BindingError::DeviceNotFound => "device_not_found",
+            BindingError::DeviceNotPoweredOn { .. } => "device_not_powered_on",
+            BindingError::BedNotFound => "bed_not_found",
+            BindingError::PatientNotFound { .. } | BindingError::UnknownPatient { .. } => "patient_not_found",
+            BindingError::PatientNotBound { .. } => "patient_not_bound",
+            BindingError::InvalidDeviceId { .. } => "invalid_device_id",
+            BindingError::PatientAlreadyBound { .. } => "patient_already_bound",
+            BindingError::DeviceInUse { .. } => "device_in_use",
+            BindingError::State(_) => "invalid_patient_state",
+            BindingError::Database(_) => "internal_error",
+        }
+    }
+}
+
+impl From<sqlx::Error> for BindingError {
+    fn from(e: sqlx::Error) -> Self {
+        BindingError::Database(e)
+    }
+}
+
+impl From<TransitionError> for BindingError {
+    fn from(e: TransitionError) -> Self {
+        BindingError::State(e)
+    }
+}
+
+/// Why a device is being detached from its patient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnbindCause {
+    Request,
+    /// The device was switched off, `completed` when the preset amount had been delivered.
+    PowerOff { delivered_amount: u16, completed: bool },
+    Rebind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingSource {
+    Mq,
+    Api,
+}
+
+impl BindingSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BindingSource::Mq => "mq",
+            BindingSource::Api => "api",
+        }
+    }
+}
+
+/// How a nurse picks the patient when binding by hand.
+#[derive(Debug, Clone)]
+pub enum PatientKey {
+    PatientNo(String),
+    BedNo(String),
+}
+
+async fn find_patient(key: PatientKey) -> Result<Patient, BindingError> {
+    match key {
+        PatientKey::PatientNo(patient_no) => fetch_patient_by_patient_no(patient_no.clone()).await?
+            .ok_or(BindingError::UnknownPatient { patient_no }),
+        PatientKey::BedNo(bed_no) => fetch_patient_by_bed_no(bed_no.clone()).await?
+            .ok_or(BindingError::PatientNotFound { bed_no }),
+    }
+}
+
+/// Outcome of a binding or unbinding request, published back to the requester and kept for audit.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BindingResult {
+    pub action: &'static str,
+    pub success: bool,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub device_mac: Option<String>,
+    pub bed_mac: Option<String>,
+    pub device_id: Option<u8>,
+    pub bed_no: Option<String>,
+    pub patient_no: Option<String>,
+}
+
+impl BindingResult {
+    pub fn new(action: &'static str, device_mac: Option<String>, bed_mac: Option<String>) -> Self {
+        Self { action, success: false, reason: None, message: None, device_mac, bed_mac, device_id: None, bed_no: None, patient_no: None }
+    }
+
+    /// A request that could not even be read, e.g. malformed JSON.
+    pub fn invalid(action: &'static str, message: String) -> Self {
+        Self { reason: Some("invalid_message".to_string()), message: Some(message), ..Self::new(action, None, None) }
+    }
+
+    fn finish(mut self, outcome: &Result<Option<Patient>, BindingError>) -> Self {
+        match outcome {
+            Ok(patient) => {
+                self.success = true;
+                if let Some(patient) = patient {
+                    self.patient_no = Some(patient.patient_no.clone());
+                    self.bed_no = Some(patient.bed_no.clone());
+                }
+            }
+            Err(e) => {
+                self.reason = Some(e.code().to_string());
+                self.message = Some(e.to_string());
+            }
+        }
+        self
+    }
+}
+
+/// Stores the attempt in `binding_attempt`; failures to do so are only logged.
+pub async fn record(result: &BindingResult, source: BindingSource) {
+    let attempt = NewBindingAttempt {
+        source: source.as_str(),
+        action: result.action,
+        device_mac: result.device_mac.clone(),
+        bed_mac: result.bed_mac.clone(),
+        device_id: result.device_id,
+        bed_no: result.bed_no.clone(),
+        patient_no: result.patient_no.clone(),
+        success: result.success,
+        reason: result.reason.clone(),
+        message: result.message.clone(),
+    };
+
+    if let Err(e) = insert_binding_attempt(attempt).await {
+        error!("record binding attempt failed: {}", e);
+    }
+}
+
+/// Pairs the device and the bed reported by the bedside hardware and records the attempt.
+pub async fn bind_by_mac(device_mac: String, bed_mac: String, source: BindingSource) -> BindingResult {
+    let mut result = BindingResult::new("bind", Some(device_mac.clone()), Some(bed_mac.clone()));
+
+    let outcome = async {
+        let device = fetch_device_by_device_mac(device_mac).await?.ok_or(BindingError::DeviceNotFound)?;
+        result.device_id = Some(device.device_id);
+
+        let bed = fetch_bed_by_bed_mac(bed_mac).await?.ok_or(BindingError::BedNotFound)?;
+        result.bed_no = Some(bed.bed_no.clone());
+
+        let patient = fetch_patient_by_bed_no(bed.bed_no.clone()).await?
+            .ok_or(BindingError::PatientNotFound { bed_no: bed.bed_no })?;
+        result.patient_no = Some(patient.patient_no.clone());
+
+        bind(&device, patient).await.map(Some)
+    }.await;
+
+    let result = result.finish(&outcome);
+    record(&result, source).await;
+    result
+}
+
+/// Binds a powered-on device to the patient. A device still attached to a previous patient that
+/// is not infusing anymore is released from that patient first.
+pub async fn bind(device: &Device, patient: Patient) -> Result<Patient, BindingError> {
+    if device.get_status() != Some(DEVICE_ON) {
+        return Err(BindingError::DeviceNotPoweredOn { device_id: device.device_id });
+    }
+
+    match patient.device_id {
+        Some(device_id) if device_id == device.device_id as u16 => return Ok(patient),
+        Some(device_id) => return Err(BindingError::PatientAlreadyBound { patient_no: patient.patient_no, device_id }),
+        None => {},
+    }
+
+    if let Some(previous) = fetch_patient_by_device_id(device.device_id).await? {
+        if fetch_active_infusion_by_device_id(device.device_id).await?.is_some() {
+            return Err(BindingError::DeviceInUse { device_id: device.device_id, patient_no: previous.patient_no });
+        }
+        unbind(device.device_id, UnbindCause::Rebind).await?;
+    }
+
+    patient_state::validate(&patient, PatientState::Bound)?;
+
+    update_patient_device_id(patient.patient_no.clone(), device.device_id).await?;
+    patient_state::transition(&patient, PatientState::Bound, TransitionCause::Bind).await?;
+    alarm::clear(AlarmType::Unbound, device.device_id).await;
+
+    info!("device {} bound to patient {}", device.device_id, patient.patient_no);
+    let mut patient = patient;
+    patient.device_id = Some(device.device_id as u16);
+    patient.status = Some(PatientState::Bound as u16);
+    Ok(patient)
+}
+
+pub async fn unbind_by_mac(device_mac: String, source: BindingSource) -> BindingResult {
+    let mut result = BindingResult::new("unbind", Some(device_mac.clone()), None);
+
+    let outcome = async {
+        let device = fetch_device_by_device_mac(device_mac).await?.ok_or(BindingError::DeviceNotFound)?;
+        result.device_id = Some(device.device_id);
+
+        unbind(device.device_id, UnbindCause::Request).await
+    }.await;
+
+    let result = result.finish(&outcome);
+    record(&result, source).await;
+    result
+}
+
+/// Manual binding for when the pairing hardware fails. Runs the same checks as the MQ path,
+/// records the attempt and publishes the result on `binding_result`.
+pub async fn bind_by_request(device_id: u8, key: PatientKey) -> BindingResult {
+    let mut result = BindingResult::new("bind", None, None);
+    result.device_id = Some(device_id);
+
+    let outcome = async {
+        let device = fetch_device_by_device_id(device_id).await?.ok_or(BindingError::DeviceNotFound)?;
+        result.device_mac = device.get_mac();
+
+        let patient = find_patient(key).await?;
+        result.patient_no = Some(patient.patient_no.clone());
+        result.bed_no = Some(patient.bed_no.clone());
+
+        bind(&device, patient).await.map(Some)
+    }.await;
+
+    let result = result.finish(&outcome);
+    record(&result, BindingSource::Api).await;
+    publish_binding_result(&result).await;
+    result
+}
+
+/// Unbinding requested by a nurse, either by device or by the patient it is attached to.
+/// Recorded and published like the hardware requests.
+pub async fn unbind_by_request(device_id: Option<u8>, key: Option<PatientKey>) -> Result<Option<Patient>, BindingError> {
+    let mut result = BindingResult::new("unbind", None, None);
+    result.device_id = device_id;
+
+    let outcome = async {
+        let device_id = match (device_id, key) {
+            (Some(device_id), _) => device_id,
+            (None, Some(key)) => {
+                let patient = find_patient(key).await?;
+                result.patient_no = Some(patient.patient_no.clone());
+                result.bed_no = Some(patient.bed_no.clone());
+
+                let device_id = patient.device_id.ok_or(BindingError::PatientNotBound { patient_no: patient.patient_no.clone() })?;
+                let device_id = u8::try_from(device_id)
+                    .map_err(|_| BindingError::InvalidDeviceId { patient_no: patient.patient_no, device_id })?;
+                result.device_id = Some(device_id);
+                device_id
+            }
+            (None, None) => return Err(BindingError::DeviceNotFound),
+        };
+
+        unbind(device_id, UnbindCause::Request).await
+    }.await;
+
+    let result = result.finish(&outcome);
+    record(&result, BindingSource::Api).await;
+    publish_binding_result(&result).await;
+
+    outcome
+}
+
+/// Detaches the device from its patient: closes the running session, releases the patient
+/// (state 6 after a completed bag, otherwise back to unbound) and lets the device raise the
+/// unbound alarm again next time it is switched on. All of it is written in one transaction.
+/// Returns the released patient, if any.
+pub async fn unbind(device_id: u8, cause: UnbindCause) -> Result<Option<Patient>, BindingError> {
+    let Some(patient) = fetch_patient_by_device_id(device_id).await? else {
+        return Ok(None);
+    };
+
+    let (final_state, delivered_amount, transition_cause) = match cause {
+        UnbindCause::PowerOff { delivered_amount, completed: true } => (InfusionFinalState::Completed, Some(delivered_amount), TransitionCause::PowerOff),
+        UnbindCause::PowerOff { delivered_amount, completed: false } => (InfusionFinalState::Stopped, Some(delivered_amount), TransitionCause::PowerOff),
+        UnbindCause::Request | UnbindCause::Rebind => (InfusionFinalState::Stopped, None, TransitionCause::Unbind),
+    };
+
+    // A pump switched off right after delivering the bag completes it before the release.
+    let from = PatientState::from_status(patient.status);
+    let steps = match from {
+        Some(PatientState::Complete) => vec![(PatientState::Released, transition_cause)],
+        Some(PatientState::Infusing | PatientState::Paused) if matches!(final_state, InfusionFinalState::Completed) => vec![
+            (PatientState::Complete, TransitionCause::InfusionComplete),
+            (PatientState::Released, transition_cause),
+        ],
+        Some(PatientState::Unbound) => vec![],
+        _ => vec![(PatientState::Unbound, transition_cause)],
+    };
+    if let Some((target, _)) = steps.first() {
+        patient_state::validate(&patient, *target)?;
+    }
+
+    let steps: Vec<(u16, &str)> = steps.iter().map(|(state, cause)| (*state as u16, cause.as_str())).collect();
+    let unbinding = apply_unbinding(device_id, &patient.patient_no, patient.status, &steps, final_state, delivered_amount)
+        .await?
+        .ok_or(TransitionError::Conflict)?;
+    infusion::stop_tracking(device_id);
+    command::forget_drip_rate_target(device_id);
+
+    if let Some(session) = &unbinding.infusion {
+        warn!("infusion {:?} of device {} closed by unbinding ({:?})", session.id, device_id, cause);
+        if matches!(final_state, InfusionFinalState::Completed) {
+            drug_plan::on_completed(session.drug_id).await;
+        }
+    }
+    for transition in &unbinding.transitions {
+        patient_state::announce(&patient, transition);
+    }
+
+    info!("device {} unbound from patient {} ({:?})", device_id, patient.patient_no, cause);
+    let mut patient = patient;
+    patient.device_id = None;
+    if let Some(transition) = unbinding.transitions.last() {
+        patient.status = Some(transition.to_state);
+    }
+    Ok(Some(patient))
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::mem::discriminant;
+use std::sync::Mutex;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+use crate::config::get_config;
+use crate::mq::{send_command, DeviceData};
+use crate::protocol::DeviceCommand;
+use crate::repository::{insert_command, update_command_attempt, update_command_state, CommandState};
+
+struct PendingCommand {
+    command: DeviceCommand,
+    ack: oneshot::Sender<()>,
+}
+
+static PENDING_COMMANDS: Lazy<Mutex<HashMap<i64, PendingCommand>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Last drip rate each device acknowledged, kept until the bag completes or the device is unbound.
+static DRIP_RATE_TARGETS: Lazy<Mutex<HashMap<u8, u8>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandReceipt {
+    pub command_id: i64,
+    pub publish_error: Option<String>,
+}
+
+/// Records the command, publishes it and keeps retrying in the background until a
+/// `device_data` frame confirms it or the retries are exhausted.
+pub async fn dispatch(command: DeviceCommand) -> Result<CommandReceipt, String> {
+    let payload = serde_json::to_string(&command).map_err(|e| e.to_string())?;
+    let command_id = insert_command(command.device_id(), payload)
+        .await
+        .map_err(|e| format!("Failed to record command: {}", e))?;
+
+    let (ack_tx, ack_rx) = oneshot::channel();
+    {
+        let mut pending = PENDING_COMMANDS.lock().unwrap();
+        // Retrying an older setting after a newer one was sent would silently undo the newer one.
+        pending.retain(|_, p| !conflicts(&p.command, &command));
+        pending.insert(command_id, PendingCommand { command, ack: ack_tx });
+    }
+
+    let publish_error = send_command(command).await.err();
+    if let Err(e) = update_command_attempt(command_id, 1, publish_error.clone()).await {
+        error!("Failed to update command {}: {}", command_id, e);
+    }
+
+    tokio::spawn(track(command_id, command, ack_rx, publish_error.clone()));
+
+    Ok(CommandReceipt { command_id, publish_error })
+}
+
+async fn track(command_id: i64, command: DeviceCommand, mut ack_rx: oneshot::Receiver<()>, mut last_error: Option<String>) {
+    let command_config = &get_config().command;
+    let ack_timeout = Duration::from_secs(command_config.ack_timeout_secs);
+    let mut attempts = 1;
+
+    loop {
+        match tokio::time::timeout(ack_timeout, &mut ack_rx).await {
+            Ok(Ok(())) => {
+                info!("command {} {:?} confirmed after {} attempts", command_id, command, attempts);
+                finish(command_id, CommandState::Confirmed, None).await;
+                return;
+            }
+            Ok(Err(_)) => {
+                warn!("command {} {:?} superseded by a newer command", command_id, command);
+                finish(command_id, CommandState::Failed, Some("superseded by a newer command".to_string())).await;
+                return;
+            }
+            Err(_) => {}
+        }
+
+        if attempts > command_config.max_retries {
+            let removed = PENDING_COMMANDS.lock().unwrap().remove(&command_id).is_some();
+            if !removed {
+                // Confirmed or superseded while the timeout fired, the receiver holds the outcome.
+                continue;
+            }
+
+            warn!("command {} {:?} not confirmed after {} attempts", command_id, command, attempts);
+            let reason = last_error.unwrap_or_else(|| "no acknowledgement from device".to_string());
+            finish(command_id, CommandState::Failed, Some(reason)).await;
+            return;
+        }
+
+        attempts += 1;
+        info!("retrying command {} {:?}, attempt {}", command_id, command, attempts);
+        last_error = send_command(command).await.err();
+        if let Err(e) = update_command_attempt(command_id, attempts, last_error.clone()).await {
+            error!("Failed to update command {}: {}", command_id, e);
+        }
+    }
+}
+
+async fn finish(command_id: i64, state: CommandState, reason: Option<String>) {
+    if let Err(e) = update_command_state(command_id, state, reason).await {
+        error!("Failed to update command {} state: {}", command_id, e);
+    }
+}
+
+/// Confirms every pending command whose effect is visible in the frame.
+pub fn on_device_data(device_data: &DeviceData) {
+    let mut pending = PENDING_COMMANDS.lock().unwrap();
+
+    let confirmed: Vec<i64> = pending.iter()
+        .filter(|(_, p)| is_acknowledged(&p.command, device_data))
+        .map(|(id, _)| *id)
+        .collect();
+
+    for id in confirmed {
+        if let Some(p) = pending.remove(&id) {
+            if let DeviceCommand::SetDripRate { device_id, drip_rate } = p.command {
+                DRIP_RATE_TARGETS.lock().unwrap().insert(device_id, drip_rate);
+            }
+            let _ = p.ack.send(());
+        }
+    }
+}
+
+/// The rate the device was last set to and confirmed, which may differ from the prescription
+/// after an audited override.
+pub fn drip_rate_target(device_id: u8) -> Option<u8> {
+    DRIP_RATE_TARGETS.lock().unwrap().get(&device_id).copied()
+}
+
+pub fn forget_drip_rate_target(device_id: u8) {
+    DRIP_RATE_TARGETS.lock().unwrap().remove(&device_id);
+}
+
+fn is_acknowledged(command: &DeviceCommand, device_data: &DeviceData) -> bool {
+    if command.device_id() != device_data.device_id {
+        return false;
+    }
+
+    match *command {
+        DeviceCommand::TurnOff { .. } => device_data.is_off(),
+        DeviceCommand::StartDrip { .. } => device_data.is_infusing(),
+        DeviceCommand::StopDrip { .. } => device_data.is_on(),
+        DeviceCommand::SetDripRate { drip_rate, .. } => drip_rate_reached(drip_rate, device_data.drip_value),
+        DeviceCommand::ModifyPresetAmount { preset_amount, .. } => device_data.preset_amount == preset_amount,
+        DeviceCommand::SetTemGear { tem_gear, .. } => device_data.tem_gear_value == tem_gear,
+    }
+}
+
+/// `drip_value` is the measured rate of a gravity drip, which rarely lands exactly on the target.
+fn drip_rate_reached(target: u8, measured: u8) -> bool {
+    let tolerance = (target as f64 * get_config().command.drip_rate_ack_tolerance_ratio).max(1.0);
+    (measured as f64 - target as f64).abs() <= tolerance
+}
+
+fn conflicts(a: &DeviceCommand, b: &DeviceCommand) -> bool {
+    if a.device_id() != b.device_id() {
+        return false;
+    }
+
+    let is_run_state = |c: &DeviceCommand| matches!(c, DeviceCommand::TurnOff { .. } | DeviceCommand::StartDrip { .. } | DeviceCommand::StopDrip { .. });
+
+    discriminant(a) == discriminant(b) || (is_run_state(a) && is_run_state(b))
+}
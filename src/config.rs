@@ -0,0 +1,362 @@
+use std::str::FromStr;
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+const DEFAULT_CONFIG_PATH: &str = "config.yaml";
+const ENV_PREFIX: &str = "SMART_INFUSION";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub mq: MqConfig,
+    pub his: HisConfig,
+    pub command: CommandConfig,
+    pub alarm: AlarmConfig,
+    pub infusion: InfusionConfig,
+    pub telemetry: TelemetryConfig,
+    pub device: DeviceConfig,
+    pub safety: SafetyConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind_addr: String,
+    pub ward: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0:3000".to_string(),
+            ward: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub path: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self { path: "data/smart_infusion.db".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub exchange: String,
+    pub reconnect_initial_backoff_secs: u64,
+    pub reconnect_max_backoff_secs: u64,
+    pub prefetch_count: u16,
+}
+
+impl Default for MqConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 5672,
+            username: "guest".to_string(),
+            password: "guest".to_string(),
+            exchange: "amq.topic".to_string(),
+            reconnect_initial_backoff_secs: 1,
+            reconnect_max_backoff_secs: 60,
+            prefetch_count: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HisConfig {
+    pub base_url: String,
+    pub sync_interval_secs: u64,
+    pub timeout_secs: u64,
+    pub page_size: u32,
+    pub page_param: String,
+    pub page_size_param: String,
+    pub first_page: u32,
+    pub max_pages: u32,
+}
+
+impl Default for HisConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "http://127.0.0.1:1024/".to_string(),
+            sync_interval_secs: 3600,
+            timeout_secs: 30,
+            page_size: 100,
+            page_param: "pageNum".to_string(),
+            page_size_param: "pageSize".to_string(),
+            first_page: 1,
+            max_pages: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommandConfig {
+    pub ack_timeout_secs: u64,
+    pub max_retries: u32,
+    pub drip_rate_ack_tolerance_ratio: f64,
+}
+
+impl Default for CommandConfig {
+    fn default() -> Self {
+        Self {
+            ack_timeout_secs: 10,
+            max_retries: 2,
+            drip_rate_ack_tolerance_ratio: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlarmConfig {
+    pub occlusion_frames: u32,
+    pub low_battery_threshold: Option<u8>,
+    pub drift_tolerance_ratio: f64,
+    pub drift_duration_secs: u64,
+    pub min_temperature: Option<u8>,
+    pub max_temperature: Option<u8>,
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        Self {
+            occlusion_frames: 3,
+            low_battery_threshold: None,
+            drift_tolerance_ratio: 0.1,
+            drift_duration_secs: 60,
+            min_temperature: None,
+            max_temperature: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct InfusionConfig {
+    pub near_empty_ratio: f64,
+    pub auto_stop: bool,
+    pub drops_per_ml: u16,
+    pub rate_window_secs: u64,
+}
+
+impl Default for InfusionConfig {
+    fn default() -> Self {
+        Self {
+            near_empty_ratio: 0.9,
+            auto_stop: false,
+            drops_per_ml: 20,
+            rate_window_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub raw_retention_hours: u32,
+    pub downsample_bucket_secs: u32,
+    pub retention_days: u32,
+    pub maintenance_interval_secs: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            raw_retention_hours: 24,
+            downsample_bucket_secs: 60,
+            retention_days: 30,
+            maintenance_interval_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    pub offline_timeout_secs: u64,
+    pub watchdog_interval_secs: u64,
+    pub power_off_status: u8,
+    /// Register of the temperature gear setting; the command is refused until it is configured.
+    pub tem_gear_register: Option<u8>,
+    pub max_tem_gear: u8,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            offline_timeout_secs: 60,
+            watchdog_interval_secs: 10,
+            power_off_status: 0,
+            tem_gear_register: None,
+            max_tem_gear: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SafetyConfig {
+    pub drip_rate_deviation: f64,
+    pub min_drip_rate: u8,
+    pub max_drip_rate: u8,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self {
+            drip_rate_deviation: 0.2,
+            min_drip_rate: 1,
+            max_drip_rate: 150,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the YAML file (if present) and applies `SMART_INFUSION_*` environment overrides on top.
+    pub fn load(path: &str) -> Result<Self> {
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(content) => serde_yaml::from_str::<Config>(&content)
+                .with_context(|| format!("Failed to parse config file {}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("config file {} not found, using defaults", path);
+                Config::default()
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read config file {}", path)),
+        };
+
+        config.apply_env_overrides()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Rejects values that would make the service misbehave rather than fail loudly.
+    fn validate(&self) -> Result<()> {
+        if self.alarm.occlusion_frames < 1 {
+            anyhow::bail!("alarm.occlusion_frames must be at least 1");
+        }
+        if self.mq.prefetch_count < 1 {
+            anyhow::bail!("mq.prefetch_count must be at least 1");
+        }
+        if self.safety.min_drip_rate > self.safety.max_drip_rate {
+            anyhow::bail!("safety.min_drip_rate {} is above safety.max_drip_rate {}", self.safety.min_drip_rate, self.safety.max_drip_rate);
+        }
+        if let (Some(min), Some(max)) = (self.alarm.min_temperature, self.alarm.max_temperature) {
+            if min > max {
+                anyhow::bail!("alarm.min_temperature {} is above alarm.max_temperature {}", min, max);
+            }
+        }
+        if [crate::mq::STATUS_ON, crate::mq::STATUS_INFUSING].contains(&self.device.power_off_status) {
+            anyhow::bail!("device.power_off_status {} collides with the on or infusing status code", self.device.power_off_status);
+        }
+
+        Ok(())
+    }
+
+    fn apply_env_overrides(&mut self) -> Result<()> {
+        override_from_env("SERVER_BIND_ADDR", &mut self.server.bind_addr)?;
+        override_from_env("SERVER_WARD", &mut self.server.ward)?;
+        override_from_env("DB_PATH", &mut self.database.path)?;
+        override_from_env("MQ_HOST", &mut self.mq.host)?;
+        override_from_env("MQ_PORT", &mut self.mq.port)?;
+        override_from_env("MQ_USERNAME", &mut self.mq.username)?;
+        override_from_env("MQ_PASSWORD", &mut self.mq.password)?;
+        override_from_env("MQ_EXCHANGE", &mut self.mq.exchange)?;
+        override_from_env("MQ_PREFETCH_COUNT", &mut self.mq.prefetch_count)?;
+        override_from_env("HIS_BASE_URL", &mut self.his.base_url)?;
+        override_from_env("HIS_SYNC_INTERVAL_SECS", &mut self.his.sync_interval_secs)?;
+        override_from_env("HIS_TIMEOUT_SECS", &mut self.his.timeout_secs)?;
+        override_from_env("HIS_PAGE_SIZE", &mut self.his.page_size)?;
+        override_from_env("COMMAND_ACK_TIMEOUT_SECS", &mut self.command.ack_timeout_secs)?;
+        override_from_env("COMMAND_MAX_RETRIES", &mut self.command.max_retries)?;
+        override_from_env("COMMAND_DRIP_RATE_ACK_TOLERANCE_RATIO", &mut self.command.drip_rate_ack_tolerance_ratio)?;
+        override_from_env("ALARM_OCCLUSION_FRAMES", &mut self.alarm.occlusion_frames)?;
+        override_from_env("ALARM_DRIFT_TOLERANCE_RATIO", &mut self.alarm.drift_tolerance_ratio)?;
+        override_from_env("ALARM_DRIFT_DURATION_SECS", &mut self.alarm.drift_duration_secs)?;
+        override_option_from_env("ALARM_MIN_TEMPERATURE", &mut self.alarm.min_temperature)?;
+        override_option_from_env("ALARM_MAX_TEMPERATURE", &mut self.alarm.max_temperature)?;
+        override_from_env("INFUSION_NEAR_EMPTY_RATIO", &mut self.infusion.near_empty_ratio)?;
+        override_from_env("INFUSION_AUTO_STOP", &mut self.infusion.auto_stop)?;
+        override_from_env("INFUSION_DROPS_PER_ML", &mut self.infusion.drops_per_ml)?;
+        override_from_env("INFUSION_RATE_WINDOW_SECS", &mut self.infusion.rate_window_secs)?;
+        override_from_env("TELEMETRY_RAW_RETENTION_HOURS", &mut self.telemetry.raw_retention_hours)?;
+        override_from_env("TELEMETRY_DOWNSAMPLE_BUCKET_SECS", &mut self.telemetry.downsample_bucket_secs)?;
+        override_from_env("TELEMETRY_RETENTION_DAYS", &mut self.telemetry.retention_days)?;
+        override_from_env("TELEMETRY_MAINTENANCE_INTERVAL_SECS", &mut self.telemetry.maintenance_interval_secs)?;
+        override_from_env("DEVICE_OFFLINE_TIMEOUT_SECS", &mut self.device.offline_timeout_secs)?;
+        override_from_env("DEVICE_WATCHDOG_INTERVAL_SECS", &mut self.device.watchdog_interval_secs)?;
+        override_from_env("DEVICE_POWER_OFF_STATUS", &mut self.device.power_off_status)?;
+        override_option_from_env("DEVICE_TEM_GEAR_REGISTER", &mut self.device.tem_gear_register)?;
+        override_from_env("DEVICE_MAX_TEM_GEAR", &mut self.device.max_tem_gear)?;
+        override_from_env("SAFETY_DRIP_RATE_DEVIATION", &mut self.safety.drip_rate_deviation)?;
+        override_from_env("SAFETY_MIN_DRIP_RATE", &mut self.safety.min_drip_rate)?;
+        override_from_env("SAFETY_MAX_DRIP_RATE", &mut self.safety.max_drip_rate)?;
+
+        Ok(())
+    }
+}
+
+fn override_from_env<T>(name: &str, target: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let key = format!("{}_{}", ENV_PREFIX, name);
+
+    if let Ok(value) = std::env::var(&key) {
+        *target = value.parse::<T>()
+            .map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", key, e))?;
+    }
+
+    Ok(())
+}
+
+/// Like `override_from_env` for optional settings; an empty value unsets them.
+fn override_option_from_env<T>(name: &str, target: &mut Option<T>) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let key = format!("{}_{}", ENV_PREFIX, name);
+
+    if let Ok(value) = std::env::var(&key) {
+        *target = match value.trim() {
+            "" => None,
+            value => Some(value.parse::<T>().map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", key, e))?),
+        };
+    }
+
+    Ok(())
+}
+
+pub fn init_config() -> Result<&'static Config> {
+    let path = std::env::var(format!("{}_CONFIG", ENV_PREFIX)).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    let config = Config::load(&path)?;
+    info!("configuration loaded from {}", path);
+
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+pub fn get_config() -> &'static Config {
+    CONFIG.get().expect("configuration is not initialized")
+}
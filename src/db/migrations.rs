@@ -0,0 +1,256 @@
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+// Append new migrations at the end with the next version number, never edit an applied one.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create device, bed, patient and drug tables",
+        sql: "CREATE TABLE IF NOT EXISTS device (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id INTEGER NOT NULL,
+                mac VARCHAR(255) NULL,
+                status INTEGER DEFAULT 0,
+                drip_value INTEGER NULL,
+                preset_amount INTEGER NULL,
+                cumulative_amount INTEGER NULL,
+                tem_value INTEGER NULL,
+                tem_gear_value INTEGER NULL,
+                power_state INTEGER NULL,
+                do_bind INTEGER Default 0
+            );
+            CREATE TABLE IF NOT EXISTS bed (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                bed_no VARCHAR(255) NOT NULL,
+                mac VARCHAR(255) NULL
+            );
+            CREATE TABLE IF NOT EXISTS patient (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                patient_no VARCHAR(255) NOT NULL,
+                name VARCHAR(255) NOT NULL,
+                gender VARCHAR(10) NULL,
+                age INTEGER NULL,
+                bed_no VARCHAR(255) NULL,
+                device_id INTEGER NULL,
+                current_drug_id VARCHAR(255) NULL,
+                current_drop_rate INTEGER NULL,
+                current_temperature INTEGER NULL,
+                total_drop INTEGER NULL,
+                status INTEGER NULL default 0
+            );
+            CREATE TABLE IF NOT EXISTS drug (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                drug_name VARCHAR(255) NOT NULL,
+                dosage INTEGER NOT NULL,
+                drip_rate INTEGER NOT NULL,
+                patient_no VARCHAR(255) NOT NULL
+            );",
+    },
+    Migration {
+        version: 2,
+        description: "create infusion session table",
+        sql: "CREATE TABLE IF NOT EXISTS infusion (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                patient_no VARCHAR(255) NOT NULL,
+                device_id INTEGER NOT NULL,
+                drug_id INTEGER NULL,
+                drug_name VARCHAR(255) NULL,
+                started_at TIMESTAMP NOT NULL,
+                ended_at TIMESTAMP NULL,
+                preset_amount INTEGER NULL,
+                delivered_amount INTEGER NULL,
+                final_state INTEGER NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_infusion_device_id ON infusion (device_id, ended_at);
+            CREATE INDEX IF NOT EXISTS idx_infusion_patient_no ON infusion (patient_no);",
+    },
+    Migration {
+        version: 3,
+        description: "deduplicate synced rows, add unique keys and sync markers",
+        sql: "DELETE FROM patient WHERE id NOT IN (SELECT MIN(id) FROM patient GROUP BY patient_no);
+            DELETE FROM device WHERE id NOT IN (SELECT MIN(id) FROM device GROUP BY device_id);
+            DELETE FROM bed WHERE id NOT IN (SELECT MIN(id) FROM bed GROUP BY bed_no);
+            DELETE FROM drug WHERE id NOT IN (SELECT MIN(id) FROM drug GROUP BY patient_no, drug_name);
+            CREATE UNIQUE INDEX IF NOT EXISTS ux_patient_patient_no ON patient (patient_no);
+            CREATE UNIQUE INDEX IF NOT EXISTS ux_device_device_id ON device (device_id);
+            CREATE UNIQUE INDEX IF NOT EXISTS ux_bed_bed_no ON bed (bed_no);
+            CREATE UNIQUE INDEX IF NOT EXISTS ux_drug_patient_no_drug_name ON drug (patient_no, drug_name);
+            ALTER TABLE patient ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE patient ADD COLUMN synced_at TIMESTAMP NULL;
+            ALTER TABLE drug ADD COLUMN synced_at TIMESTAMP NULL;",
+    },
+    Migration {
+        version: 4,
+        description: "create device command table",
+        sql: "CREATE TABLE IF NOT EXISTS device_command (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id INTEGER NOT NULL,
+                command TEXT NOT NULL,
+                state INTEGER NOT NULL DEFAULT 0,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT NULL,
+                created_at TIMESTAMP NOT NULL,
+                updated_at TIMESTAMP NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_device_command_device_id ON device_command (device_id, state);",
+    },
+    Migration {
+        version: 5,
+        description: "create alarm table",
+        sql: "CREATE TABLE IF NOT EXISTS alarm (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                alarm_type VARCHAR(64) NOT NULL,
+                device_id INTEGER NULL,
+                patient_no VARCHAR(255) NULL,
+                patient_name VARCHAR(255) NULL,
+                bed_no VARCHAR(255) NULL,
+                message TEXT NULL,
+                state INTEGER NOT NULL DEFAULT 0,
+                raised_at TIMESTAMP NOT NULL,
+                acknowledged_at TIMESTAMP NULL,
+                acknowledged_by VARCHAR(255) NULL,
+                resolved_at TIMESTAMP NULL,
+                resolved_by VARCHAR(255) NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_alarm_state ON alarm (state, alarm_type);
+            CREATE INDEX IF NOT EXISTS idx_alarm_device_id ON alarm (device_id, alarm_type);",
+    },
+    Migration {
+        version: 6,
+        description: "add alarm severity",
+        sql: "ALTER TABLE alarm ADD COLUMN severity INTEGER NOT NULL DEFAULT 2;
+            UPDATE alarm SET severity = 3 WHERE alarm_type = 'sos';",
+    },
+    Migration {
+        version: 7,
+        description: "add infusion drops per ml",
+        sql: "ALTER TABLE infusion ADD COLUMN drops_per_ml INTEGER NULL;",
+    },
+    Migration {
+        version: 8,
+        description: "create patient transition table",
+        sql: "CREATE TABLE IF NOT EXISTS patient_transition (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                patient_no VARCHAR(255) NOT NULL,
+                from_state INTEGER NULL,
+                to_state INTEGER NOT NULL,
+                cause VARCHAR(64) NOT NULL,
+                created_at TIMESTAMP NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_patient_transition_patient_no ON patient_transition (patient_no);",
+    },
+    Migration {
+        version: 9,
+        description: "create telemetry table",
+        sql: "CREATE TABLE IF NOT EXISTS telemetry (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id INTEGER NOT NULL,
+                patient_no VARCHAR(255) NULL,
+                drip_value INTEGER NOT NULL,
+                preset_amount INTEGER NOT NULL,
+                cumulative_amount INTEGER NOT NULL,
+                tem_value INTEGER NOT NULL,
+                tem_gear_value INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                power_state INTEGER NOT NULL,
+                samples INTEGER NOT NULL DEFAULT 1,
+                downsampled INTEGER NOT NULL DEFAULT 0,
+                recorded_at TIMESTAMP NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_telemetry_device_id ON telemetry (device_id, recorded_at);
+            CREATE INDEX IF NOT EXISTS idx_telemetry_patient_no ON telemetry (patient_no, recorded_at);",
+    },
+    Migration {
+        version: 10,
+        description: "add device last seen and online",
+        sql: "ALTER TABLE device ADD COLUMN last_seen_at TIMESTAMP NULL;
+            ALTER TABLE device ADD COLUMN online INTEGER NOT NULL DEFAULT 0;",
+    },
+    Migration {
+        version: 11,
+        description: "create binding attempt table",
+        sql: "CREATE TABLE IF NOT EXISTS binding_attempt (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                source VARCHAR(16) NOT NULL,
+                action VARCHAR(16) NOT NULL,
+                device_mac VARCHAR(255) NULL,
+                bed_mac VARCHAR(255) NULL,
+                device_id INTEGER NULL,
+                bed_no VARCHAR(255) NULL,
+                patient_no VARCHAR(255) NULL,
+                success INTEGER NOT NULL,
+                reason VARCHAR(64) NULL,
+                message TEXT NULL,
+                created_at TIMESTAMP NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_binding_attempt_device_id ON binding_attempt (device_id);",
+    },
+    Migration {
+        version: 12,
+        description: "create drip rate override table",
+        sql: "CREATE TABLE IF NOT EXISTS drip_rate_override (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id INTEGER NOT NULL,
+                patient_no VARCHAR(255) NULL,
+                drug_id INTEGER NULL,
+                drug_name VARCHAR(255) NULL,
+                prescribed_rate INTEGER NULL,
+                min_rate INTEGER NOT NULL,
+                max_rate INTEGER NOT NULL,
+                requested_rate INTEGER NOT NULL,
+                reason TEXT NOT NULL,
+                command_id INTEGER NULL,
+                created_at TIMESTAMP NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_drip_rate_override_device_id ON drip_rate_override (device_id);",
+    },
+    Migration {
+        version: 13,
+        description: "create drug library table",
+        sql: "CREATE TABLE IF NOT EXISTS drug_library (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                drug_code VARCHAR(64) NOT NULL,
+                drug_name VARCHAR(255) NOT NULL,
+                concentration VARCHAR(64) NULL,
+                min_drip_rate INTEGER NULL,
+                max_drip_rate INTEGER NULL,
+                soft_min_drip_rate INTEGER NULL,
+                soft_max_drip_rate INTEGER NULL,
+                max_volume INTEGER NULL,
+                tem_gear INTEGER NULL,
+                updated_at TIMESTAMP NULL
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS ux_drug_library_drug_code ON drug_library (drug_code);
+            CREATE INDEX IF NOT EXISTS idx_drug_library_drug_name ON drug_library (drug_name);",
+    },
+    Migration {
+        version: 14,
+        description: "add drug plan order and state",
+        sql: "ALTER TABLE drug ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE drug ADD COLUMN state INTEGER NOT NULL DEFAULT 0;
+            UPDATE drug SET seq = id;",
+    },
+    Migration {
+        version: 15,
+        description: "add drug library temperature limits",
+        sql: "ALTER TABLE drug_library ADD COLUMN min_temperature INTEGER NULL;
+            ALTER TABLE drug_library ADD COLUMN max_temperature INTEGER NULL;",
+    },
+    Migration {
+        version: 16,
+        description: "make drug library names unique",
+        sql: "DELETE FROM drug_library WHERE id NOT IN (SELECT MAX(id) FROM drug_library GROUP BY drug_name);
+            DROP INDEX IF EXISTS idx_drug_library_drug_name;
+            CREATE UNIQUE INDEX IF NOT EXISTS ux_drug_library_drug_name ON drug_library (drug_name);",
+    },
+    Migration {
+        version: 17,
+        description: "key the drug plan by position instead of drug name",
+        sql: "DELETE FROM drug WHERE id NOT IN (SELECT MAX(id) FROM drug GROUP BY patient_no, seq);
+            DROP INDEX IF EXISTS ux_drug_patient_no_drug_name;
+            CREATE UNIQUE INDEX IF NOT EXISTS ux_drug_patient_no_seq ON drug (patient_no, seq);",
+    },
+];
@@ -0,0 +1,75 @@
+mod migrations;
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous};
+use tracing::{info, error};
+
+use crate::config::get_config;
+use migrations::MIGRATIONS;
+
+pub static DB_POOL: Lazy<Arc<SqlitePool>> = Lazy::new(|| {
+    let db_path = &get_config().database.path;
+
+    if let Some(parent) = std::path::Path::new(db_path).parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent).expect("Failed to create database directory");
+        }
+    }
+
+    let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path))
+        .expect("Invalid database path")
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(Duration::from_secs(5));
+
+    Arc::new(SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect_lazy_with(options))
+});
+
+pub async fn init_db() -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description VARCHAR(255) NOT NULL,
+            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )"
+    )
+    .execute(db.as_ref())
+    .await?;
+
+    let current_version: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_version")
+        .fetch_one(db.as_ref())
+        .await?;
+    info!("current schema version: {}", current_version);
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current_version) {
+        let mut tx = db.begin().await?;
+
+        if let Err(e) = sqlx::query(migration.sql).execute(&mut *tx).await {
+            error!("migration {} ({}) failed: {}", migration.version, migration.description, e);
+            return Err(e);
+        }
+
+        sqlx::query("INSERT INTO schema_version (version, description) VALUES (?, ?)")
+            .bind(migration.version)
+            .bind(migration.description)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        info!("migration {} ({}) applied", migration.version, migration.description);
+    }
+
+    Ok(())
+}
+
+pub fn get_db() -> Arc<SqlitePool> {
+    DB_POOL.clone()
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use tracing::info;
+
+use crate::repository::{fetch_drug_library_entry, upsert_drug_library, DrugLibraryEntry};
+
+#[derive(Debug)]
+pub enum DrugLibraryError {
+    Invalid { drug_code: String, reason: &'static str },
+    Yaml(serde_yaml::Error),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for DrugLibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DrugLibraryError::Invalid { drug_code, reason } => write!(f, "invalid drug {}: {}", drug_code, reason),
+            DrugLibraryError::Yaml(e) => write!(f, "invalid yaml: {}", e),
+            DrugLibraryError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DrugLibraryError {}
+
+impl From<sqlx::Error> for DrugLibraryError {
+    fn from(e: sqlx::Error) -> Self {
+        DrugLibraryError::Database(e)
+    }
+}
+
+impl From<serde_yaml::Error> for DrugLibraryError {
+    fn from(e: serde_yaml::Error) -> Self {
+        DrugLibraryError::Yaml(e)
+    }
+}
+
+fn validate(entry: &DrugLibraryEntry) -> Result<(), DrugLibraryError> {
+    let invalid = |reason| Err(DrugLibraryError::Invalid { drug_code: entry.drug_code.clone(), reason });
+
+    if entry.drug_code.trim().is_empty() || entry.drug_name.trim().is_empty() {
+        return invalid("drugCode and drugName are required");
+    }
+
+    let hard_min = entry.min_drip_rate.unwrap_or(u16::MIN);
+    let hard_max = entry.max_drip_rate.unwrap_or(u16::MAX);
+    let soft_min = entry.soft_min_drip_rate.unwrap_or(hard_min);
+    let soft_max = entry.soft_max_drip_rate.unwrap_or(hard_max);
+
+    if hard_min > hard_max || soft_min > soft_max {
+        return invalid("minimum drip rate above maximum");
+    }
+
+    if soft_min < hard_min || soft_max > hard_max {
+        return invalid("soft limits must lie within the hard limits");
+    }
+
+    if entry.min_temperature.unwrap_or(u8::MIN) > entry.max_temperature.unwrap_or(u8::MAX) {
+        return invalid("minimum temperature above maximum");
+    }
+
+    Ok(())
+}
+
+/// Prescriptions are matched by name, so a name may only belong to one drug code.
+async fn check_names(entries: &[DrugLibraryEntry]) -> Result<(), DrugLibraryError> {
+    for (i, entry) in entries.iter().enumerate() {
+        let invalid = |reason| Err(DrugLibraryError::Invalid { drug_code: entry.drug_code.clone(), reason });
+
+        if entries[..i].iter().any(|other| other.drug_name == entry.drug_name) {
+            return invalid("drugName appears more than once");
+        }
+
+        let existing = fetch_drug_library_entry(&entry.drug_name).await?;
+        if existing.is_some_and(|existing| existing.drug_code != entry.drug_code) {
+            return invalid("drugName is already used by another drugCode");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn save(entry: DrugLibraryEntry) -> Result<(), DrugLibraryError> {
+    validate(&entry)?;
+    check_names(std::slice::from_ref(&entry)).await?;
+    upsert_drug_library(&[entry]).await?;
+    Ok(())
+}
+
+/// Imports a YAML list of entries. Nothing is written unless every entry is valid.
+pub async fn import_yaml(content: &str) -> Result<usize, DrugLibraryError> {
+    let entries: Vec<DrugLibraryEntry> = serde_yaml::from_str(content)?;
+    for entry in entries.iter() {
+        validate(entry)?;
+    }
+    check_names(&entries).await?;
+
+    upsert_drug_library(&entries).await?;
+    info!("imported {} drug library entries", entries.len());
+
+    Ok(entries.len())
+}
+
@@ -0,0 +1,135 @@
+use std::fmt;
+use tracing::{error, info};
+
+use crate::command::CommandReceipt;
+use crate::patient_state::PatientState;
+use crate::repository::{activate_drug, complete_drug, fetch_current_drug, fetch_next_drug, fetch_patient_by_patient_no, Drug, DrugState, Patient};
+use crate::safety::{self, SafetyError};
+
+#[derive(Debug)]
+pub enum PlanError {
+    PatientNotFound { patient_no: String },
+    NoDevice { patient_no: String },
+    InvalidDeviceId { patient_no: String, device_id: u16 },
+    NotReady { patient_no: String, status: Option<u16> },
+    CurrentNotFinished { drug_name: String },
+    PlanFinished { patient_no: String },
+    InvalidDripRate { drug_name: String, drip_rate: u16 },
+    Safety { drug: Drug, error: SafetyError },
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::PatientNotFound { patient_no } => write!(f, "patient {} not found", patient_no),
+            PlanError::NoDevice { patient_no } => write!(f, "patient {} has no device bound", patient_no),
+            PlanError::InvalidDeviceId { patient_no, device_id } => write!(f, "patient {} is bound to invalid device id {}", patient_no, device_id),
+            PlanError::NotReady { patient_no, status } => write!(f, "patient {} is still infusing (status {:?})", patient_no, status),
+            PlanError::CurrentNotFinished { drug_name } => write!(f, "{} has not been infused yet", drug_name),
+            PlanError::PlanFinished { patient_no } => write!(f, "patient {} has no drug left to infuse", patient_no),
+            PlanError::InvalidDripRate { drug_name, drip_rate } => write!(f, "prescribed drip rate {} of {} cannot be sent to the device", drip_rate, drug_name),
+            PlanError::Safety { drug, error } => write!(f, "{} was not confirmed, the device was not set up: {}", drug.drug_name, error),
+            PlanError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+impl From<sqlx::Error> for PlanError {
+    fn from(e: sqlx::Error) -> Self {
+        PlanError::Database(e)
+    }
+}
+
+/// Marks the drug of a freshly started session as the one hanging, for bags started on the device
+/// without going through `confirm_next`.
+pub async fn on_started(patient: &Patient, drug_id: Option<i64>) {
+    let Some(drug_id) = drug_id else {
+        return;
+    };
+
+    if patient.current_drug_id.as_deref() == Some(drug_id.to_string().as_str()) {
+        return;
+    }
+
+    match activate_drug(patient.patient_no.clone(), drug_id).await {
+        Ok(()) => info!("drug {} of patient {} started", drug_id, patient.patient_no),
+        Err(e) => error!("activate drug {} failed: {}", drug_id, e),
+    }
+}
+
+pub async fn on_completed(drug_id: Option<i64>) {
+    let Some(drug_id) = drug_id else {
+        return;
+    };
+
+    if let Err(e) = complete_drug(drug_id).await {
+        error!("complete drug {} failed: {}", drug_id, e);
+    }
+}
+
+/// Nurse confirmation that the next bag of the plan is hung: checks its prescription against the
+/// safety limits, sets the device up and only then makes it the current drug, so a rejected or
+/// failed command leaves the plan unchanged.
+pub async fn confirm_next(patient_no: String, nurse: Option<String>) -> Result<Drug, PlanError> {
+    let patient = fetch_patient_by_patient_no(patient_no.clone()).await?
+        .ok_or(PlanError::PatientNotFound { patient_no: patient_no.clone() })?;
+
+    let Some(device_id) = patient.device_id else {
+        return Err(PlanError::NoDevice { patient_no });
+    };
+    let device_id = u8::try_from(device_id)
+        .map_err(|_| PlanError::InvalidDeviceId { patient_no: patient_no.clone(), device_id })?;
+
+    if !matches!(PatientState::from_status(patient.status), Some(PatientState::Bound | PatientState::Complete)) {
+        return Err(PlanError::NotReady { patient_no, status: patient.status });
+    }
+
+    if let Some(current) = fetch_current_drug(&patient).await? {
+        if current.state == DrugState::Active as u8 {
+            return Err(PlanError::CurrentNotFinished { drug_name: current.drug_name });
+        }
+    }
+
+    let mut drug = fetch_next_drug(patient_no.clone()).await?
+        .ok_or(PlanError::PlanFinished { patient_no: patient_no.clone() })?;
+    let Some(drug_id) = drug.id else {
+        return Err(PlanError::PlanFinished { patient_no });
+    };
+
+    let Ok(drip_rate) = u8::try_from(drug.drip_rate) else {
+        return Err(PlanError::InvalidDripRate { drug_name: drug.drug_name, drip_rate: drug.drip_rate });
+    };
+
+    let limits = safety::limits_for_drug(device_id, Some(&patient), Some(&drug)).await?;
+    let checked = safety::check_preset_amount(&limits, drug.dosage)
+        .and_then(|_| safety::check_drip_rate(&limits, drip_rate, None));
+    if let Err(error) = checked {
+        return Err(PlanError::Safety { drug, error });
+    }
+
+    // A command that could not be published is only retried in the background; the drug stays
+    // pending until the nurse confirms again, which supersedes the retry.
+    let dispatched = async {
+        safety::dispatch_preset_amount(limits.clone(), drug.dosage).await.and_then(published)?;
+        safety::dispatch_drip_rate(limits, drip_rate, None).await.and_then(published)
+    }.await;
+    if let Err(error) = dispatched {
+        return Err(PlanError::Safety { drug, error });
+    }
+
+    activate_drug(patient_no.clone(), drug_id).await?;
+    drug.state = DrugState::Active as u8;
+    info!("next drug {} of patient {} confirmed by {:?}", drug.drug_name, patient_no, nurse);
+
+    Ok(drug)
+}
+
+fn published(receipt: CommandReceipt) -> Result<CommandReceipt, SafetyError> {
+    match receipt.publish_error {
+        Some(e) => Err(SafetyError::Command(e)),
+        None => Ok(receipt),
+    }
+}
@@ -1,10 +1,11 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use anyhow::{Result, Context};
-use std::time::Duration;
-use crate::{api, db::get_db, repository::{insert_beds, insert_devices, insert_drugs, insert_patients, Bed, Device, Drug, Patient}};
+use chrono::Local;
+use std::{future::Future, time::Duration};
+use crate::{config::get_config, repository::{archive_unsynced_patients, delete_unsynced_drugs, upsert_beds, upsert_devices, upsert_drugs, upsert_patients, Bed, Device, Drug, Patient}};
 use tracing::{info, error};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ApiPatient {
     patient_no: String,
@@ -15,7 +16,7 @@ struct ApiPatient {
     bed_no: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ApiPatientDrugList {
     drug_name: String,
@@ -35,7 +36,7 @@ impl From<ApiPatientDrugList> for Drug {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ApiBed {
     bed_no: String,
@@ -47,7 +48,7 @@ impl From<ApiBed> for Bed {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ApiDevice {
     dev_no: u8,
@@ -62,26 +63,32 @@ impl From<ApiDevice> for Device {
 pub struct HttpClient {
     client: reqwest::Client,
     api_base_url: String,
+    page_size: u32,
 }
 
 impl HttpClient {
     pub fn new(api_base_url: String) -> Self {
         let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
+            .timeout(Duration::from_secs(get_config().his.timeout_secs))
             .build()
             .expect("Failed to create HTTP client");
 
         Self {
             client,
             api_base_url,
+            page_size: get_config().his.page_size.max(1),
         }
     }
 
-    pub async fn fetch_and_store_patients(&self) -> Result<()> {
-        let url = format!("{}patientInfoDashboard/queryList", self.api_base_url);
-        info!("Fetching patients data from API {}", url);
+    async fn fetch_page<T: DeserializeOwned>(&self, path: &str, page: u32) -> Result<Vec<T>> {
+        let his_config = &get_config().his;
+        let url = format!("{}{}", self.api_base_url, path);
 
         let response = self.client.get(&url)
+            .query(&[
+                (his_config.page_param.as_str(), page),
+                (his_config.page_size_param.as_str(), self.page_size),
+            ])
             .send()
             .await
             .context("Failed to send request to API")?;
@@ -93,107 +100,121 @@ impl HttpClient {
             ));
         }
 
-        let api_response: Vec<ApiPatient> = response.json()
+        response.json()
             .await
-            .context("Failed to parse API response")?;
-        info!("Patients data parsed successfully");
-        
-        
-        let patients: Vec<Patient> = api_response.iter().map(|p| {
-            Patient::new(
-                p.patient_no.clone(), 
-                p.patient_name.clone(), 
-                p.gender.clone(), 
-                p.age, 
-                p.bed_no.clone(),
-                None
-            )
-        }).collect();
-        match insert_patients(patients).await {
-            Ok(_) => info!("Patients data stored successfully"),
-            Err(e) => error!("Patients data stored failed: {}", e)
-        };
-
-        let mut all_drugs = Vec::new();
-        for patient in &api_response {
-            for drug in &patient.drug_list {
-                let mut drug_obj = Drug::new(
-                    drug.drug_name.clone(), 
-                    drug.dosage, 
-                    drug.drip_rate
-                );
-                drug_obj.set_patient_no(patient.patient_no.clone());
-                all_drugs.push(drug_obj);
+            .context("Failed to parse API response")
+    }
+
+    /// Walks the paged endpoint and hands every page to `store` as soon as it arrives, stopping at
+    /// the first short page. Returns the total number of items received. A walk that hits
+    /// `his.max_pages` or gets the same page twice (an endpoint ignoring the paging parameters) is
+    /// an error, so callers never treat a partial list as complete.
+    async fn for_each_page<T, F, Fut>(&self, path: &str, mut store: F) -> Result<usize>
+    where
+        T: DeserializeOwned + PartialEq + Clone,
+        F: FnMut(Vec<T>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let his_config = &get_config().his;
+        let mut total = 0;
+        let mut previous: Option<Vec<T>> = None;
+
+        for page in his_config.first_page..his_config.first_page.saturating_add(his_config.max_pages) {
+            let items: Vec<T> = self.fetch_page(path, page).await
+                .with_context(|| format!("Failed to fetch page {} of {}", page, path))?;
+            let count = items.len();
+            info!("Fetched page {} of {} with {} items", page, path, count);
+
+            if count > 0 && previous.as_ref() == Some(&items) {
+                return Err(anyhow::anyhow!("Page {} of {} repeats the previous page, the endpoint ignores paging", page, path));
+            }
+
+            if count > 0 {
+                store(items.clone()).await?;
+                total += count;
+            }
+
+            if count < self.page_size as usize {
+                return Ok(total);
             }
+            previous = Some(items);
         }
-        
-        match insert_drugs(all_drugs).await {
-            Ok(_) => info!("Drugs data stored successfully"),
-            Err(e) => error!("Drugs data stored failed: {}", e)
-        };
 
-        Ok(())
+        Err(anyhow::anyhow!("Stopped fetching {} after {} pages without reaching the end", path, his_config.max_pages))
     }
 
-    pub async fn fetch_and_store_beds(&self) -> Result<()> {
-        info!("Fetching beds data from API...");
+    pub async fn fetch_and_store_patients(&self) -> Result<()> {
+        info!("Fetching patients data from API {}", self.api_base_url);
+
+        let synced_at = Local::now().naive_local();
+
+        let patient_count = self.for_each_page("patientInfoDashboard/queryList", |api_patients: Vec<ApiPatient>| async move {
+            let mut all_drugs = Vec::new();
+            for patient in &api_patients {
+                for (seq, drug) in patient.drug_list.iter().enumerate() {
+                    let mut drug_obj = Drug::new(
+                        drug.drug_name.clone(), 
+                        drug.dosage, 
+                        drug.drip_rate
+                    );
+                    drug_obj.set_patient_no(patient.patient_no.clone());
+                    drug_obj.set_seq(seq as u16);
+                    all_drugs.push(drug_obj);
+                }
+            }
 
-        let url = format!("{}patientInfoDashboard/getRemoteBedInfo", self.api_base_url);
-        
-        let response = self.client.get(&url)
-            .send()
-            .await
-            .context("Failed to send request to API")?;
-        
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "API request failed with status: {}", 
-                response.status()
-            ));
+            let patients = api_patients.into_iter().map(|p| p.into()).collect();
+            upsert_patients(patients, synced_at)
+                .await
+                .context("Failed to store patients data")?;
+
+            upsert_drugs(all_drugs, synced_at)
+                .await
+                .context("Failed to store drugs data")?;
+
+            Ok(())
+        }).await?;
+        info!("{} patients data stored successfully", patient_count);
+
+        // An empty answer is more likely an upstream hiccup than an empty ward, so keep local data.
+        if patient_count > 0 {
+            match archive_unsynced_patients(synced_at).await {
+                Ok(count) => info!("{} patients no longer listed by HIS were archived", count),
+                Err(e) => error!("Failed to archive patients: {}", e)
+            };
+            match delete_unsynced_drugs(synced_at).await {
+                Ok(count) => info!("{} drugs no longer listed by HIS were removed", count),
+                Err(e) => error!("Failed to remove drugs: {}", e)
+            };
         }
-        
-        let beds: Vec<ApiBed> = response.json()
-            .await
-            .context("Failed to parse API response")?;
-        info!("Beds data parsed successfully");
-
-        let beds = beds.into_iter().map(|bed| bed.into()).collect();
-        match insert_beds(beds).await {
-            Ok(_) => println!("Beds data stored successfully"),
-            Err(e) => println!("Beds data stored failed: {}", e)
-        };
-        info!("Beds data stored successfully");
+
+        Ok(())
+    }
+
+    pub async fn fetch_and_store_beds(&self) -> Result<()> {
+        info!("Fetching beds data from API {}", self.api_base_url);
+
+        let bed_count = self.for_each_page("patientInfoDashboard/getRemoteBedInfo", |beds: Vec<ApiBed>| async move {
+            let beds = beds.into_iter().map(|bed| bed.into()).collect();
+            upsert_beds(beds)
+                .await
+                .context("Failed to store beds data")
+        }).await?;
+        info!("{} beds data stored successfully", bed_count);
         
         Ok(())
     }
 
     pub async fn fetch_and_store_devices(&self) -> Result<()> {
-        let url = format!("{}patientInfoDashboard/getRemoteInfusionDeviceData", self.api_base_url);
-        info!("Fetching devices data from API {}", url);
-        
-        let response = self.client.get(&url)
-            .send()
-            .await
-            .context("Failed to send request to API")?;
-        
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "API request failed with status: {}", 
-                response.status()
-            ));
-        }
-        
-        let devices: Vec<ApiDevice> = response.json()
-            .await
-            .context("Failed to parse API response")?;
-        info!("Devices data parsed successfully");
-        
-        let devices = devices.into_iter().map(|device| device.into()).collect();
-        match insert_devices(devices).await {
-            Ok(_) => println!("Devices data stored successfully"),
-            Err(e) => println!("Devices data stored failed: {}", e)
-        };
-        info!("Devices data stored successfully");
+        info!("Fetching devices data from API {}", self.api_base_url);
+
+        let device_count = self.for_each_page("patientInfoDashboard/getRemoteInfusionDeviceData", |devices: Vec<ApiDevice>| async move {
+            let devices = devices.into_iter().map(|device| device.into()).collect();
+            upsert_devices(devices)
+                .await
+                .context("Failed to store devices data")
+        }).await?;
+        info!("{} devices data stored successfully", device_count);
         
         Ok(())
     }
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use chrono::Local;
+use once_cell::sync::Lazy;
+use tracing::{error, info, warn};
+
+use crate::alarm;
+use crate::command;
+use crate::drug_plan;
+use crate::config::get_config;
+use crate::mq::DeviceData;
+use crate::protocol::DeviceCommand;
+use crate::repository::{finish_infusion, update_infusion_drops_per_ml, AlarmType, InfusionEstimate, InfusionFinalState, Patient};
+
+/// Devices whose delivered volume passed the near-empty threshold of the current preset.
+static NEAR_EMPTY: Lazy<Mutex<HashSet<u8>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Devices whose delivered volume reached the current preset.
+static COMPLETED: Lazy<Mutex<HashSet<u8>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Recent drip rate samples and the latest progress of every infusing device.
+static RATE_TRACKERS: Lazy<Mutex<HashMap<u8, RateTracker>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct RateTracker {
+    samples: VecDeque<(Instant, u8)>,
+    preset_amount: u16,
+    cumulative_amount: u16,
+    drops_per_ml: u16,
+}
+
+impl RateTracker {
+    fn average_drip_rate(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.samples.iter().map(|(_, rate)| *rate as f64).sum::<f64>() / self.samples.len() as f64
+    }
+
+    fn estimate(&self, device_id: u8) -> InfusionEstimate {
+        let remaining_amount = self.preset_amount.saturating_sub(self.cumulative_amount);
+        let drip_rate = self.average_drip_rate();
+        let ml_per_min = drip_rate / self.drops_per_ml.max(1) as f64;
+
+        let remaining_secs = (ml_per_min > 0.0).then(|| (remaining_amount as f64 / ml_per_min * 60.0).round() as u64);
+        let eta = remaining_secs.and_then(|secs| chrono::Duration::try_seconds(secs as i64))
+            .map(|remaining| Local::now().naive_local() + remaining);
+
+        InfusionEstimate {
+            device_id,
+            remaining_amount,
+            drip_rate,
+            drops_per_ml: self.drops_per_ml,
+            remaining_secs,
+            eta,
+        }
+    }
+}
+
+/// Adds an infusing frame to the rolling drip rate window and returns the refreshed estimate.
+pub fn track_rate(device_data: &DeviceData, drops_per_ml: Option<u16>) -> InfusionEstimate {
+    let window = Duration::from_secs(get_config().infusion.rate_window_secs);
+    let now = Instant::now();
+
+    let mut trackers = RATE_TRACKERS.lock().unwrap();
+    let tracker = trackers.entry(device_data.device_id).or_insert_with(|| RateTracker {
+        samples: VecDeque::new(),
+        preset_amount: 0,
+        cumulative_amount: 0,
+        drops_per_ml: get_config().infusion.drops_per_ml,
+    });
+
+    tracker.samples.push_back((now, device_data.drip_value));
+    while tracker.samples.front().is_some_and(|(at, _)| now.duration_since(*at) > window) {
+        tracker.samples.pop_front();
+    }
+    tracker.preset_amount = device_data.preset_amount;
+    tracker.cumulative_amount = device_data.cumulative_amount;
+    if let Some(drops_per_ml) = drops_per_ml {
+        tracker.drops_per_ml = drops_per_ml;
+    }
+
+    tracker.estimate(device_data.device_id)
+}
+
+/// Drops the rate window once the device is no longer infusing.
+pub fn stop_tracking(device_id: u8) {
+    RATE_TRACKERS.lock().unwrap().remove(&device_id);
+}
+
+pub fn estimate(device_id: u8) -> Option<InfusionEstimate> {
+    RATE_TRACKERS.lock().unwrap().get(&device_id).map(|tracker| tracker.estimate(device_id))
+}
+
+/// Calibrates the infusion set of the device's running session. Returns `false` when it has none.
+pub async fn calibrate(device_id: u8, drops_per_ml: u16) -> Result<bool, sqlx::Error> {
+    if !update_infusion_drops_per_ml(device_id, drops_per_ml).await? {
+        return Ok(false);
+    }
+
+    if let Some(tracker) = RATE_TRACKERS.lock().unwrap().get_mut(&device_id) {
+        tracker.drops_per_ml = drops_per_ml;
+    }
+
+    Ok(true)
+}
+
+pub fn is_complete(device_data: &DeviceData) -> bool {
+    device_data.preset_amount > 0 && device_data.cumulative_amount >= device_data.preset_amount
+}
+
+fn is_near_empty(device_data: &DeviceData) -> bool {
+    let threshold = device_data.preset_amount as f64 * get_config().infusion.near_empty_ratio;
+    device_data.preset_amount > 0 && device_data.cumulative_amount as f64 >= threshold
+}
+
+/// Raises the near-empty alarm once the delivered volume crosses the threshold, and clears the
+/// near-empty and complete alarms when a new bag starts below it.
+pub async fn check_progress(device_data: &DeviceData, patient: Option<&Patient>) {
+    let near_empty = is_near_empty(device_data);
+    let (newly_near_empty, refilled) = {
+        let mut flagged = NEAR_EMPTY.lock().unwrap();
+        if near_empty {
+            (flagged.insert(device_data.device_id), false)
+        } else {
+            (false, flagged.remove(&device_data.device_id))
+        }
+    };
+
+    if newly_near_empty {
+        let message = format!("{} of {} delivered", device_data.cumulative_amount, device_data.preset_amount);
+        alarm::raise(AlarmType::NearEmpty, device_data.device_id, patient, Some(message)).await;
+    }
+
+    if refilled {
+        alarm::clear(AlarmType::NearEmpty, device_data.device_id).await;
+    }
+
+    if !is_complete(device_data) && COMPLETED.lock().unwrap().remove(&device_data.device_id) {
+        alarm::clear(AlarmType::InfusionComplete, device_data.device_id).await;
+    }
+}
+
+/// Closes the running session as completed. Returns `true` only for the frame that completed it,
+/// which also raises the alarm and, when configured, stops the drip.
+pub async fn complete(device_data: &DeviceData, patient: Option<&Patient>) -> bool {
+    COMPLETED.lock().unwrap().insert(device_data.device_id);
+    stop_tracking(device_data.device_id);
+
+    let infusion = match finish_infusion(device_data.device_id, Some(device_data.cumulative_amount), InfusionFinalState::Completed).await {
+        Ok(Some(infusion)) => infusion,
+        Ok(None) => return false,
+        Err(e) => {
+            error!("finish infusion failed: {}", e);
+            return false;
+        }
+    };
+
+    info!("infusion {:?} of device {} completed", infusion.id, device_data.device_id);
+    drug_plan::on_completed(infusion.drug_id).await;
+    command::forget_drip_rate_target(device_data.device_id);
+
+    NEAR_EMPTY.lock().unwrap().remove(&device_data.device_id);
+    alarm::clear(AlarmType::NearEmpty, device_data.device_id).await;
+
+    let message = format!("{} of {} delivered", device_data.cumulative_amount, device_data.preset_amount);
+    alarm::raise(AlarmType::InfusionComplete, device_data.device_id, patient, Some(message)).await;
+
+    if get_config().infusion.auto_stop && device_data.is_infusing() {
+        match command::dispatch(DeviceCommand::StopDrip { device_id: device_data.device_id }).await {
+            Ok(receipt) => info!("auto stop of device {} sent as command {}", device_data.device_id, receipt.command_id),
+            Err(e) => warn!("auto stop of device {} failed: {}", device_data.device_id, e),
+        }
+    }
+
+    true
+}
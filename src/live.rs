@@ -0,0 +1,121 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Query;
+use axum::response::IntoResponse;
+use chrono::{Local, NaiveDateTime};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use crate::config::get_config;
+use crate::mq::DeviceData;
+use crate::repository::{AlarmRecord, InfusionEstimate};
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+static LIVE_CHANNEL: Lazy<broadcast::Sender<LiveMessage>> = Lazy::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum LiveEvent {
+    DeviceData(DeviceData),
+    PatientStatus { from: Option<u16>, to: u16, cause: String },
+    InfusionEstimate(InfusionEstimate),
+    AlarmRaised(AlarmRecord),
+    AlarmUpdated(AlarmRecord),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveMessage {
+    pub ward: String,
+    pub patient_no: Option<String>,
+    pub bed_no: Option<String>,
+    pub timestamp: NaiveDateTime,
+    #[serde(flatten)]
+    pub event: LiveEvent,
+}
+
+/// Subscription filter, given as query parameters on connect and replaceable at any time by
+/// sending the same fields as a JSON text message. Lists are comma separated.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveFilter {
+    pub ward: Option<String>,
+    pub bed_no: Option<String>,
+    pub patient_no: Option<String>,
+}
+
+impl LiveFilter {
+    fn matches(&self, message: &LiveMessage) -> bool {
+        matches_list(&self.ward, Some(&message.ward))
+            && matches_list(&self.bed_no, message.bed_no.as_ref())
+            && matches_list(&self.patient_no, message.patient_no.as_ref())
+    }
+}
+
+fn matches_list(filter: &Option<String>, value: Option<&String>) -> bool {
+    match filter.as_deref().map(str::trim).filter(|f| !f.is_empty()) {
+        None => true,
+        Some(filter) => value.is_some_and(|v| filter.split(',').any(|f| f.trim() == v)),
+    }
+}
+
+/// Pushes an event to every connected client whose filter matches. Dropped silently when nobody listens.
+pub fn broadcast(event: LiveEvent, patient_no: Option<String>, bed_no: Option<String>) {
+    let message = LiveMessage {
+        ward: get_config().server.ward.clone(),
+        patient_no,
+        bed_no,
+        timestamp: Local::now().naive_local(),
+        event,
+    };
+
+    let _ = LIVE_CHANNEL.send(message);
+}
+
+pub async fn live_feed(ws: WebSocketUpgrade, Query(filter): Query<LiveFilter>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, filter))
+}
+
+async fn handle_socket(mut socket: WebSocket, mut filter: LiveFilter) {
+    let mut rx = LIVE_CHANNEL.subscribe();
+    info!("live feed client connected with filter {:?}", filter);
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Ok(message) => {
+                    if !filter.matches(&message) {
+                        continue;
+                    }
+                    let text = match serde_json::to_string(&message) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            warn!("failed to serialize live message: {}", e);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => warn!("live feed client lagged, {} messages skipped", skipped),
+                Err(RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<LiveFilter>(&text) {
+                    Ok(new_filter) => {
+                        info!("live feed client changed filter to {:?}", new_filter);
+                        filter = new_filter;
+                    }
+                    Err(e) => warn!("invalid live feed filter {}: {}", text, e),
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    info!("live feed client disconnected");
+}
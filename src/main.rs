@@ -2,17 +2,29 @@ use std::time::Duration;
 
 use api::{sync_remote_bed_data, sync_remote_device_data, sync_remote_patient_data};
 use axum::{routing::{get, post}, Router};
-use http_client::HttpClient;
 use tracing::{info, error, Level};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 // use winapi::um::wincon::FreeConsole;
 
+mod alarm;
 mod api;
+mod binding;
+mod command;
+mod config;
 mod db;
+mod drug_library;
+mod drug_plan;
 mod mq;
+mod protocol;
 mod repository;
 mod http_client;
+mod infusion;
+mod live;
+mod patient_state;
+mod safety;
+mod telemetry;
+mod watchdog;
 
 #[tokio::main]
 async fn main() {
@@ -33,21 +45,41 @@ async fn main() {
 
     info!("Start the application");
 
+    let config = match config::init_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to load configuration: {:#}", e);
+            return;
+        }
+    };
+
     // Don't show console window
     // unsafe {
     //     FreeConsole();
     // }
 
-    db::init_db().await;
+    if let Err(e) = db::init_db().await {
+        error!("Database initialization failed: {}", e);
+        return;
+    }
     info!("Database initialization completed");
+
+    match repository::fail_pending_commands("service restarted before acknowledgement").await {
+        Ok(count) if count > 0 => info!("{} pending commands from the previous run marked as failed", count),
+        Ok(_) => {},
+        Err(e) => error!("Failed to clean up pending commands: {}", e),
+    }
+
+    match alarm::restore().await {
+        Ok(count) if count > 0 => info!("{} open alarms from the previous run restored", count),
+        Ok(_) => {},
+        Err(e) => error!("Failed to restore open alarms: {}", e),
+    }
     
-    tokio::spawn(async {
-        mq::init_mq().await;
-    });
-    info!("MQ initialization completed");
-    
-    let http_client = HttpClient::new("https://api.example.com".to_string());
+    tokio::spawn(mq::init_mq());
+    info!("MQ supervisor started");
     
+    let sync_interval = Duration::from_secs(config.his.sync_interval_secs);
     tokio::spawn(async move {
         loop {
             info!("Start fetching data from API...");
@@ -56,10 +88,13 @@ async fn main() {
             sync_remote_device_data().await;
             sync_remote_bed_data().await;
             
-            tokio::time::sleep(Duration::from_secs(3600)).await;
+            tokio::time::sleep(sync_interval).await;
         }
     });
     
+    tokio::spawn(telemetry::run_maintenance());
+    tokio::spawn(watchdog::run());
+
     let app = Router::new()
         .route("/", get(handle))
         .route("/syncPatientData", get(api::sync_remote_patient_data))
@@ -69,17 +104,40 @@ async fn main() {
         .route("/syncBedData", get(api::sync_remote_bed_data))
         .route("/fetchBedData", get(api::fetch_beds))
         .route("/patientDetail", get(api::patient_detail))
+        .route("/bindDevice", post(api::bind_device))
+        .route("/unbindDevice", post(api::unbind_device))
+        .route("/bindingAttempts", get(api::binding_attempts))
+        .route("/infusionLimits", get(api::infusion_limits))
+        .route("/confirmNextDrug", post(api::confirm_next_drug))
+        .route("/drugLibrary", get(api::drug_library))
+        .route("/saveDrugLibraryEntry", post(api::save_drug_library_entry))
+        .route("/deleteDrugLibraryEntry", post(api::delete_drug_library_entry))
+        .route("/importDrugLibrary", post(api::import_drug_library))
+        .route("/dripRateOverrides", get(api::drip_rate_overrides))
+        .route("/patientTransitions", get(api::patient_transitions))
+        .route("/telemetry", get(api::telemetry_history))
+        .route("/activeInfusions", get(api::active_infusions))
+        .route("/calibrateDropsPerMl", post(api::calibrate_drops_per_ml))
+        .route("/infusionHistory", get(api::infusion_history))
         .route("/modifyDripRate", post(api::modify_drip_rate))
         .route("/turnOffDevice", post(api::turn_off_device))
         .route("/startDrip", post(api::start_drip))
         .route("/stopDrip", post(api::stop_drip))
-        .route("/modifyPresetAmount", post(api::modify_preset_amount));
+        .route("/modifyPresetAmount", post(api::modify_preset_amount))
+        .route("/setTemGear", post(api::set_tem_gear))
+        .route("/commandStatus", get(api::command_status))
+        .route("/mqStatus", get(api::mq_status))
+        .route("/alarms", get(api::alarms))
+        .route("/sosAlarms", get(api::sos_alarms))
+        .route("/acknowledgeAlarm", post(api::acknowledge_alarm))
+        .route("/resolveAlarm", post(api::resolve_alarm))
+        .route("/live", get(live::live_feed));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+    let listener = tokio::net::TcpListener::bind(&config.server.bind_addr)
         .await
-        .expect("Failed to start server on 0.0.0.0:3000");
+        .unwrap_or_else(|e| panic!("Failed to start server on {}: {}", config.server.bind_addr, e));
 
-    info!("HTTP server started on 0.0.0.0:3000");
+    info!("HTTP server started on {}", config.server.bind_addr);
     axum::serve(listener, app).await.unwrap();
 }
 
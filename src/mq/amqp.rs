@@ -1,36 +1,146 @@
-use amqprs::{callbacks::{DefaultChannelCallback, DefaultConnectionCallback}, channel::{BasicConsumeArguments, BasicPublishArguments, Channel, QueueBindArguments, QueueDeclareArguments}, connection::{Connection, OpenConnectionArguments}, consumer::{AsyncConsumer, DefaultConsumer}, BasicProperties};
-use once_cell::sync::OnceCell;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{error, info};
+use amqprs::{callbacks::{ChannelCallback, ConnectionCallback}, channel::{BasicAckArguments, BasicConsumeArguments, BasicPublishArguments, BasicQosArguments, Channel, QueueBindArguments, QueueDeclareArguments}, connection::{Connection, OpenConnectionArguments}, consumer::AsyncConsumer, error::Error as AmqpError, Ack, BasicProperties, Cancel, Close, CloseChannel, Deliver, Nack, Return};
+use axum::async_trait;
+use chrono::{Local, NaiveDateTime};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tracing::{error, info, warn};
 use std::collections::HashMap;
 
-use super::{binding::BindingConsumer, device_data_consumer::DeviceDataConsumer};
+use crate::config::get_config;
 
-static AMQP_MANAGER: OnceCell<Arc<Mutex<AmqpManager>>> = OnceCell::new();
+use super::{binding::{BindingConsumer, UnbindingConsumer}, device_data_consumer::DeviceDataConsumer};
+
+static AMQP_MANAGER: Lazy<RwLock<Option<Arc<Mutex<AmqpManager>>>>> = Lazy::new(|| RwLock::new(None));
+static MQ_STATUS: Lazy<RwLock<MqStatus>> = Lazy::new(|| RwLock::new(MqStatus::new(MqState::Connecting, None, 0)));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MqState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MqStatus {
+    pub state: MqState,
+    pub since: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub reconnect_attempts: u32,
+}
+
+impl MqStatus {
+    fn new(state: MqState, last_error: Option<String>, reconnect_attempts: u32) -> Self {
+        Self { state, since: Local::now().naive_local(), last_error, reconnect_attempts }
+    }
+}
+
+/// Acks every delivery once the consumer has handled it. Unacked deliveries would be requeued
+/// on reconnect and replayed, e.g. an old power-off frame unbinding the current patient.
+struct Acking<C>(C);
+
+#[async_trait]
+impl<C: AsyncConsumer + Send + Sync> AsyncConsumer for Acking<C> {
+    async fn consume(&mut self, channel: &Channel, deliver: Deliver, basic_properties: BasicProperties, content: Vec<u8>) {
+        let delivery_tag = deliver.delivery_tag();
+        self.0.consume(channel, deliver, basic_properties, content).await;
+
+        if let Err(e) = channel.basic_ack(BasicAckArguments::new(delivery_tag, false)).await {
+            error!("ack delivery {} on channel {} failed: {}", delivery_tag, channel, e);
+        }
+    }
+}
+
+/// Wakes the supervisor up when the broker closes the connection or one of our channels.
+struct SupervisedConnectionCallback {
+    closed: Arc<Notify>,
+}
+
+#[async_trait]
+impl ConnectionCallback for SupervisedConnectionCallback {
+    async fn close(&mut self, connection: &Connection, close: Close) -> Result<(), AmqpError> {
+        error!("connection {} closed by broker: {}", connection, close);
+        self.closed.notify_one();
+        Ok(())
+    }
+
+    async fn blocked(&mut self, connection: &Connection, reason: String) {
+        warn!("connection {} blocked by broker: {}", connection, reason);
+    }
+
+    async fn unblocked(&mut self, connection: &Connection) {
+        info!("connection {} unblocked by broker", connection);
+    }
+
+    async fn secret_updated(&mut self, connection: &Connection) {
+        info!("connection {} secret updated by broker", connection);
+    }
+}
+
+struct SupervisedChannelCallback {
+    routing_key: String,
+    closed: Arc<Notify>,
+}
+
+#[async_trait]
+impl ChannelCallback for SupervisedChannelCallback {
+    async fn close(&mut self, channel: &Channel, close: CloseChannel) -> Result<(), AmqpError> {
+        error!("channel {} for '{}' closed by broker: {}", channel, self.routing_key, close);
+        self.closed.notify_one();
+        Ok(())
+    }
+
+    async fn cancel(&mut self, channel: &Channel, cancel: Cancel) -> Result<(), AmqpError> {
+        warn!("consumer on channel {} for '{}' cancelled by broker: {}", channel, self.routing_key, cancel.consumer_tag());
+        self.closed.notify_one();
+        Ok(())
+    }
+
+    async fn flow(&mut self, channel: &Channel, active: bool) -> Result<bool, AmqpError> {
+        info!("channel {} for '{}' flow active: {}", channel, self.routing_key, active);
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, _channel: &Channel, _ack: Ack) {}
+
+    async fn publish_nack(&mut self, channel: &Channel, nack: Nack) {
+        warn!("publish nacked on channel {} for '{}': {}", channel, self.routing_key, nack.delivery_tag());
+    }
+
+    async fn publish_return(&mut self, channel: &Channel, ret: Return, _basic_properties: BasicProperties, _content: Vec<u8>) {
+        warn!("message returned on channel {} for '{}': {}", channel, self.routing_key, ret);
+    }
+}
 
 pub struct AmqpManager {
     connection: Connection,
     channels: HashMap<String, Channel>, 
+    closed: Arc<Notify>,
 }
 
 impl AmqpManager {
-    async fn new() -> Result<Self, Box<dyn std::error::Error>> {
+    async fn new(closed: Arc<Notify>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mq_config = &get_config().mq;
         let connection = Connection::open(&OpenConnectionArguments::new(
-            "127.0.0.1",
-            5672,
-            "admin",
-            "120111432@qq.com",
+            &mq_config.host,
+            mq_config.port,
+            &mq_config.username,
+            &mq_config.password,
         ))
         .await?;
 
         connection
-            .register_callback(DefaultConnectionCallback)
+            .register_callback(SupervisedConnectionCallback { closed: closed.clone() })
             .await?;
             
         Ok(Self {
             connection,
             channels: HashMap::new(),
+            closed,
         })
     }
 
@@ -47,7 +157,7 @@ impl AmqpManager {
             }
         };
         channel
-            .register_callback(DefaultChannelCallback)
+            .register_callback(SupervisedChannelCallback { routing_key: routing_key.to_string(), closed: self.closed.clone() })
             .await?;
         
         self.channels.insert(routing_key.to_string(), channel);
@@ -98,13 +208,17 @@ impl AmqpManager {
         let channel = self.channels.get(routing_key)
             .ok_or(format!("未找到routing_key '{}'对应的通道", routing_key))?;
 
+        channel
+            .basic_qos(BasicQosArguments::new(0, get_config().mq.prefetch_count, false))
+            .await?;
+
         let args = BasicConsumeArguments::new(
             queue_name,
             consumer_tag,
         );
         
         channel
-            .basic_consume(consumer, args)
+            .basic_consume(Acking(consumer), args)
             .await?;
 
         Ok(())
@@ -141,129 +255,99 @@ impl AmqpManager {
 }
 
 
-pub async fn init_mq() -> Result<(), Box<dyn std::error::Error>> {
-    let mut manager = match AmqpManager::new().await {
-        Ok(manager) => manager,
-        Err(e) => {
-            eprintln!("Failed to create AmqpManager: {}", e);
-            return Err(e);
-        }
-    };
-    
-    let exchange_name = "amq.topic";
-    
-    let device_data_routing_key = "device_data";
-    match manager.register_channel(device_data_routing_key).await {
-        Ok(_) => println!("device data channel is registered"),
-        Err(e) => {
-            eprintln!("Failed to register device data channel: {}", e);
-            return Err(e);
-        }
-    }
-    let device_data_queue = match manager.declare_queue(device_data_routing_key, "device_data_queue").await {
-        Ok(queue) => {
-            println!("device_data_queue declared: {}", queue);
-            queue
-        },
-        Err(e) => {
-            eprintln!("Failed to declare device_status_queue: {}", e);
-            return Err(e);
-        }
-    };
-    match manager.bind_queue(&device_data_queue, device_data_routing_key, exchange_name).await {
-        Ok(_) => println!("device_data_queue bound"),
-        Err(e) => {
-            eprintln!("Failed to bind device_data_queue: {}", e);
-            return Err(e);
-        }
-    }
-    match manager.setup_consumer(
-        &device_data_queue,
-        device_data_routing_key,
-        "device_data_consumer_tag",
-        DeviceDataConsumer
-    ).await {
-        Ok(_) => println!("device_data_consumer_tag setup"),
-        Err(e) => {
-            eprintln!("Failed to setup device_status_consumer_tag: {}", e);
-            return Err(e);
-        }
-    };
-
-    //binding queue
-    let binding_routing_key = "binding";
-    let binding_queue_name = "binding_queue";
-    match manager.register_channel(binding_routing_key).await {
-        Ok(_) => println!("binding_routing_key registered"),
-        Err(e) => {
-            eprintln!("Failed to register binding_routing_key: {}", e);
-            return Err(e);
-        }
-    }
-    let binding_queue = match manager.declare_queue(binding_routing_key, binding_queue_name).await {
-        Ok(queue) => {
-            println!("binding_queue declared: {}", queue);
-            queue
-        },
-        Err(e) => {
-            eprintln!("Failed to declare binding_queue: {}", e);
-            return Err(e);
-        }
-    };
-    match manager.bind_queue(&binding_queue, binding_routing_key, exchange_name).await {
-        Ok(_) => println!("binding_queue bound"),
-        Err(e) => {
-            eprintln!("Failed to bind binding_queue: {}", e);
-            return Err(e);
-        }
-    }
-    match manager.setup_consumer(
-        &binding_queue,
-        binding_routing_key,
-        "binding_consumer_tag",
-        BindingConsumer
-    ).await {
-        Ok(_) => println!("binding_consumer_tag setup"),
-        Err(e) => {
-            eprintln!("Failed to setup binding_consumer_tag: {}", e);
-            return Err(e);
-        }
-    };
-
-    let alarm_routing_key = "alarm";
-    match manager.register_channel(alarm_routing_key).await {
-        Ok(_) => println!("alarm_routing_key registered"),
-        Err(e) => {
-            eprintln!("Failed to register alarm_routing_key: {}", e);
-            return Err(e);
-        }
-    }
+async fn setup_consumer_queue<C: AsyncConsumer + Send + Sync + 'static>(
+    manager: &mut AmqpManager,
+    exchange_name: &str,
+    routing_key: &str,
+    queue_name: &str,
+    consumer_tag: &str,
+    consumer: C,
+) -> Result<(), Box<dyn std::error::Error>> {
+    manager.register_channel(routing_key).await
+        .map_err(|e| format!("Failed to register {} channel: {}", routing_key, e))?;
 
-    let controll_device_routing_key = "controll_device";
-    match manager.register_channel(controll_device_routing_key).await {
-        Ok(_) => println!("controll_device_routing_key registered"),
-        Err(e) => {
-            eprintln!("Failed to register controll_device_routing_key: {}", e);
-            return Err(e);
-        }
-    }
+    let queue = manager.declare_queue(routing_key, queue_name).await
+        .map_err(|e| format!("Failed to declare {}: {}", queue_name, e))?;
 
-    let manager = Arc::new(Mutex::new(manager));
-    if AMQP_MANAGER.set(manager).is_err() {
-        error!("AmqpManager 已经初始化");
-    }
+    manager.bind_queue(&queue, routing_key, exchange_name).await
+        .map_err(|e| format!("Failed to bind {}: {}", queue_name, e))?;
 
-    start_listening().await;
+    manager.setup_consumer(&queue, routing_key, consumer_tag, consumer).await
+        .map_err(|e| format!("Failed to setup {}: {}", consumer_tag, e))?;
 
+    info!("{} consuming {}", consumer_tag, queue);
     Ok(())
 }
 
-pub fn get_amqp_manager() -> Option<Arc<Mutex<AmqpManager>>> {
-    AMQP_MANAGER.get().cloned()
+/// Opens a connection and (re)declares every queue, binding, consumer and publishing channel.
+async fn connect(closed: Arc<Notify>) -> Result<AmqpManager, Box<dyn std::error::Error>> {
+    let mut manager = AmqpManager::new(closed).await?;
+    let exchange_name = get_config().mq.exchange.clone();
+
+    setup_consumer_queue(&mut manager, &exchange_name, "device_data", "device_data_queue", "device_data_consumer_tag", DeviceDataConsumer).await?;
+    setup_consumer_queue(&mut manager, &exchange_name, "binding", "binding_queue", "binding_consumer_tag", BindingConsumer).await?;
+    setup_consumer_queue(&mut manager, &exchange_name, "unbinding", "unbinding_queue", "unbinding_consumer_tag", UnbindingConsumer).await?;
+
+    for routing_key in ["alarm", "controll_device", "binding_result"] {
+        manager.register_channel(routing_key).await
+            .map_err(|e| format!("Failed to register {} channel: {}", routing_key, e))?;
+        info!("{} channel registered", routing_key);
+    }
+
+    Ok(manager)
 }
 
-pub async fn start_listening() {
-    std::future::pending::<()>().await;
+fn set_mq_status(state: MqState, last_error: Option<String>, reconnect_attempts: u32) {
+    *MQ_STATUS.write().unwrap() = MqStatus::new(state, last_error, reconnect_attempts);
+}
+
+/// Keeps the broker connection alive for the lifetime of the service, reconnecting with
+/// exponential backoff whenever the connection or one of its channels goes away.
+pub async fn init_mq() {
+    let mq_config = &get_config().mq;
+    let initial_backoff = Duration::from_secs(mq_config.reconnect_initial_backoff_secs.max(1));
+    let max_backoff = Duration::from_secs(mq_config.reconnect_max_backoff_secs).max(initial_backoff);
+    let mut backoff = initial_backoff;
+    let mut reconnect_attempts = 0;
+
+    loop {
+        set_mq_status(MqState::Connecting, None, reconnect_attempts);
+        let closed = Arc::new(Notify::new());
+
+        let connected = connect(closed.clone()).await.map_err(|e| e.to_string());
+        let last_error = match connected {
+            Ok(manager) => {
+                let connection = manager.connection.clone();
+                *AMQP_MANAGER.write().unwrap() = Some(Arc::new(Mutex::new(manager)));
+                set_mq_status(MqState::Connected, None, 0);
+                info!("MQ connected to {}:{}", mq_config.host, mq_config.port);
+                backoff = initial_backoff;
+                reconnect_attempts = 0;
+
+                tokio::select! {
+                    _ = closed.notified() => {},
+                    _ = connection.listen_network_io_failure() => {},
+                }
+
+                AMQP_MANAGER.write().unwrap().take();
+                "connection lost".to_string()
+            }
+            Err(e) => e,
+        };
+
+        reconnect_attempts += 1;
+        error!("MQ disconnected ({}), reconnecting in {:?}", last_error, backoff);
+        set_mq_status(MqState::Disconnected, Some(last_error), reconnect_attempts);
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
 }
 
+pub fn get_amqp_manager() -> Option<Arc<Mutex<AmqpManager>>> {
+    AMQP_MANAGER.read().unwrap().clone()
+}
 
+pub fn get_mq_status() -> MqStatus {
+    MQ_STATUS.read().unwrap().clone()
+}
@@ -1,8 +1,10 @@
-use amqprs::{channel::Channel, consumer::AsyncConsumer, BasicProperties, Deliver};
+use amqprs::{channel::{BasicPublishArguments, Channel}, consumer::AsyncConsumer, BasicProperties, Deliver};
 use axum::async_trait;
 use serde::{Deserialize, Serialize};
-use tracing::{info, error, Level};
-use crate::repository::{fetch_bed_by_bed_mac, fetch_device_by_device_mac, fetch_drug_by_patient_no, fetch_patient_by_bed_no, insert_infusion, update_device_status, update_patient_by_device_id, update_patient_device_id, Infusion};
+use tracing::{info, error};
+use crate::binding::{self, BindingResult, BindingSource};
+
+use super::publish_binding_result;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -11,66 +13,85 @@ pub struct Binding {
     pub bed_mac: String, 
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Unbinding {
+    pub device_mac: String,
+}
+
 pub struct BindingConsumer;
 
 #[async_trait]
 impl AsyncConsumer for BindingConsumer {
-    async fn consume(&mut self, channel: &Channel, deliver: Deliver, basic_properties: BasicProperties, content: Vec<u8>) {
+    async fn consume(&mut self, channel: &Channel, _deliver: Deliver, basic_properties: BasicProperties, content: Vec<u8>) {
         let msg = String::from_utf8_lossy(&content);
         
-        let binding = match serde_json::from_str::<Binding>(&msg) {
-            Ok(binding) => binding,
+        let result = match serde_json::from_str::<Binding>(&msg) {
+            Ok(binding) => binding::bind_by_mac(binding.device_mac, binding.bed_mac, BindingSource::Mq).await,
             Err(e) => {
                 error!("Failed to parse binding: {}", e);
-                return;
-            }
-        };
-        
-        let device = match fetch_device_by_device_mac(binding.device_mac).await {
-            Ok(Some(device)) => device,
-            Ok(None) => {
-                error!("Device not found");
-                return;
-            },
-            Err(e) => {
-                error!("Failed to fetch device: {}", e);
-                return;
+                let result = BindingResult::invalid("bind", e.to_string());
+                binding::record(&result, BindingSource::Mq).await;
+                result
             }
         };
 
-        let bed = match fetch_bed_by_bed_mac(binding.bed_mac).await {
-            Ok(Some(bed)) => bed,
-            Ok(None) => {
-                error!("Bed not found");
-                return;
-            }
-            Err(e) => {
-                error!("Failed to fetch bed: {}", e);
-                return;
-            }
-        };
+        match &result.reason {
+            None => info!("Device {:?} bound to patient {:?}", result.device_mac, result.patient_no),
+            Some(reason) => error!("Binding of device {:?} to bed {:?} rejected: {}", result.device_mac, result.bed_mac, reason),
+        }
 
-        let patient = match fetch_patient_by_bed_no(bed.bed_no).await {
-            Ok(Some(patient)) => patient,
-            Ok(None) => {
-                error!("Patient not found");
-                return;
-            },
+        reply(channel, &basic_properties, &result).await;
+    }
+}
+
+pub struct UnbindingConsumer;
+
+#[async_trait]
+impl AsyncConsumer for UnbindingConsumer {
+    async fn consume(&mut self, channel: &Channel, _deliver: Deliver, basic_properties: BasicProperties, content: Vec<u8>) {
+        let msg = String::from_utf8_lossy(&content);
+
+        let result = match serde_json::from_str::<Unbinding>(&msg) {
+            Ok(unbinding) => binding::unbind_by_mac(unbinding.device_mac, BindingSource::Mq).await,
             Err(e) => {
-                error!("Failed to fetch patient: {}", e);
-                return;
+                error!("Failed to parse unbinding: {}", e);
+                let result = BindingResult::invalid("unbind", e.to_string());
+                binding::record(&result, BindingSource::Mq).await;
+                result
             }
         };
 
-        if device.get_status().is_some() && device.get_status() == Some(1) {
-            match update_patient_device_id(patient.patient_no.clone(), device.device_id).await {
-                Ok(_) => {
-                    info!("Patient device id updated");
-                }
-                Err(e) => {
-                    error!("Failed to update patient device id: {}", e);
-                }
-            };
+        match &result.reason {
+            None => info!("Device {:?} unbound from patient {:?}", result.device_mac, result.patient_no),
+            Some(reason) => error!("Unbinding of device {:?} rejected: {}", result.device_mac, reason),
         }
+
+        reply(channel, &basic_properties, &result).await;
+    }
+}
+
+/// Answers on the request's `reply_to` queue when given, otherwise on the `binding_result` routing key.
+async fn reply(channel: &Channel, basic_properties: &BasicProperties, result: &BindingResult) {
+    let Some(reply_to) = basic_properties.reply_to() else {
+        publish_binding_result(result).await;
+        return;
+    };
+
+    let content = match serde_json::to_vec(result) {
+        Ok(content) => content,
+        Err(e) => {
+            error!("mq content serialize error: {}", e);
+            return;
+        }
+    };
+
+    let mut properties = BasicProperties::default();
+    if let Some(correlation_id) = basic_properties.correlation_id() {
+        properties.with_correlation_id(correlation_id);
+    }
+
+    if let Err(e) = channel.basic_publish(properties, content, BasicPublishArguments::new("", reply_to)).await {
+        error!("Failed to reply binding result to {}: {}", reply_to, e);
     }
 }
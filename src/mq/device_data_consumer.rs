@@ -5,17 +5,44 @@ use amqprs::channel::Channel;
 use amqprs::{BasicProperties, Deliver};
 use axum::async_trait;
 use serde::{Deserialize, Serialize};
-use tracing::{info, error, Level};
+use tracing::{info, error, warn, Level};
 
-use crate::repository::{update_device_status, update_patient_by_device_id};
+use crate::alarm;
+use crate::binding::{self, UnbindCause};
+use crate::command;
+use crate::config::get_config;
+use crate::drug_plan;
+use crate::infusion;
+use crate::live::{self, LiveEvent};
+use crate::patient_state::{self, PatientState, TransitionCause};
+use crate::protocol::{Frame, FrameError, DEVICE_DATA_FRAME_LEN};
+use crate::repository::{fetch_patient_by_device_id, insert_telemetry, touch_device, AlarmType, finish_infusion, set_device_powered_off, start_infusion, update_device_status, update_infusion_progress, update_patient_by_device_id, InfusionFinalState, Patient};
+
+const SOS_PRESSED: u8 = 115;
+
+/// Status byte values reported by the device. The power-off value is configured as
+/// `device.power_off_status`; any other value is decoded as `DeviceStatus::Unknown`.
+pub const STATUS_ON: u8 = 85;
+pub const STATUS_INFUSING: u8 = 17;
+
+/// Offsets of the `device_data` fields within the frame body, i.e. after header and device id.
+const DRIP_VALUE: usize = 9;
+const PRESET_AMOUNT: usize = 10;
+const CUMULATIVE_AMOUNT: usize = 12;
+const TEM_GEAR_VALUE: usize = 14;
+const TEM_VALUE: usize = 15;
+const STATUS: usize = 19;
+const POWER_STATE: usize = 20;
+const SOS_STATE: usize = 23;
 
 enum DeviceStatus {
     OFF = 0,
     ON = 1,
-    ING = 2
+    ING = 2,
+    Unknown = 3,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DeviceData {
     pub device_id: u8, //设备编号
     pub drip_value: u8, //滴速
@@ -29,29 +56,55 @@ pub struct DeviceData {
 }
 
 impl DeviceData {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
-        if bytes.len() < 28 {
-            return Err(format!("the length of the data is less than 28, it is {}", bytes.len()));
-        }
+    /// The length must match exactly: the checksum is the last byte, so a longer buffer is not a
+    /// `device_data` frame with trailing bytes but a different frame whose checksum sits elsewhere.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+        Self::decode(bytes, get_config().device.power_off_status)
+    }
+
+    fn decode(bytes: &[u8], power_off_status: u8) -> Result<Self, FrameError> {
+        let frame = Frame::decode_exact(bytes, DEVICE_DATA_FRAME_LEN)?;
+        let body = &frame.body;
         
         let data = DeviceData {
-            device_id: bytes[2],
-            drip_value: bytes[12],
-            preset_amount: bytes[13] as u16 * 256 + bytes[14] as u16,
-            cumulative_amount: bytes[15] as u16 * 256 + bytes[16] as u16,
-            tem_gear_value: bytes[17],
-            tem_value: bytes[18],
-            status: match bytes[22] {
-                85 => DeviceStatus::ON as u8,
-                17 => DeviceStatus::ING as u8,
-                _ => DeviceStatus::OFF as u8,
+            device_id: frame.device_id,
+            drip_value: body[DRIP_VALUE],
+            preset_amount: u16::from_be_bytes([body[PRESET_AMOUNT], body[PRESET_AMOUNT + 1]]),
+            cumulative_amount: u16::from_be_bytes([body[CUMULATIVE_AMOUNT], body[CUMULATIVE_AMOUNT + 1]]),
+            tem_gear_value: body[TEM_GEAR_VALUE],
+            tem_value: body[TEM_VALUE],
+            status: match body[STATUS] {
+                STATUS_ON => DeviceStatus::ON as u8,
+                STATUS_INFUSING => DeviceStatus::ING as u8,
+                code if code == power_off_status => DeviceStatus::OFF as u8,
+                _ => DeviceStatus::Unknown as u8,
             },
-            power_state: bytes[23],
-            sos_state: bytes[26],
+            power_state: body[POWER_STATE],
+            sos_state: body[SOS_STATE],
         };
         
         Ok(data)
     }
+
+    pub fn is_sos(&self) -> bool {
+        self.sos_state == SOS_PRESSED
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.status == DeviceStatus::OFF as u8
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.status == DeviceStatus::ON as u8
+    }
+
+    pub fn is_infusing(&self) -> bool {
+        self.status == DeviceStatus::ING as u8
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.status == DeviceStatus::Unknown as u8
+    }
 }
 
 pub struct DeviceDataConsumer;
@@ -62,23 +115,116 @@ impl AsyncConsumer for DeviceDataConsumer {
     async fn consume(&mut self, channel: &Channel, deliver: Deliver, basic_properties: BasicProperties, content: Vec<u8>) {
         match DeviceData::from_bytes(&content) {
             Ok(device_data) => {
+                command::on_device_data(&device_data);
+
+                match touch_device(device_data.device_id).await {
+                    Ok(true) => {
+                        info!("device {} is back online", device_data.device_id);
+                        alarm::clear(AlarmType::DeviceOffline, device_data.device_id).await;
+                    },
+                    Ok(false) => {},
+                    Err(e) => error!("update device last seen failed: {}", e),
+                }
+
+                if device_data.is_unknown() {
+                    warn!("device {} reported an unknown status in {:02X?}, frame ignored", device_data.device_id, content);
+                    return;
+                }
+
+                let patient = match fetch_patient_by_device_id(device_data.device_id).await {
+                    Ok(patient) => patient,
+                    Err(e) => {
+                        error!("fetch patient by device id failed: {}", e);
+                        None
+                    }
+                };
+                let patient_no = patient.as_ref().map(|p| p.patient_no.clone());
+                let bed_no = patient.as_ref().map(|p| p.bed_no.clone());
+                live::broadcast(LiveEvent::DeviceData(device_data.clone()), patient_no.clone(), bed_no.clone());
+
+                if let Err(e) = insert_telemetry(&device_data, patient_no.clone()).await {
+                    error!("insert telemetry failed: {}", e);
+                }
+
+                alarm::evaluate(&device_data, patient.as_ref()).await;
+
                 if device_data.status == DeviceStatus::ON as u8 {
                     println!("收到设备开机消息{:?}", device_data);
                     match update_device_status(device_data.device_id, device_data.status).await {
-                        Ok(_) => {},
+                        Ok(true) => {
+                            alarm::raise(AlarmType::Unbound, device_data.device_id, None, None).await;
+                        },
+                        Ok(false) => {},
                         Err(e) => error!("update device status failed: {}", e),
                     }
                 }
+                if device_data.status != DeviceStatus::ING as u8 {
+                    infusion::stop_tracking(device_data.device_id);
+                }
+                if device_data.status == DeviceStatus::ON as u8 && infusion::is_complete(&device_data) {
+                    infusion::complete(&device_data, patient.as_ref()).await;
+                    move_patient(patient.as_ref(), PatientState::Complete, TransitionCause::InfusionComplete).await;
+                } else if device_data.status == DeviceStatus::ON as u8 {
+                    // Stopped from the device in the middle of a bag.
+                    if patient.as_ref().is_some_and(|p| PatientState::from_status(p.status) == Some(PatientState::Infusing)) {
+                        move_patient(patient.as_ref(), PatientState::Paused, TransitionCause::DeviceStopped).await;
+                    }
+                }
+                if device_data.status == DeviceStatus::OFF as u8 {
+                    if let Err(e) = set_device_powered_off(device_data.device_id, device_data.status).await {
+                        error!("update status of device {} on power off failed: {}", device_data.device_id, e);
+                    }
+                    let completed = infusion::is_complete(&device_data);
+                    if patient.is_some() {
+                        let cause = UnbindCause::PowerOff { delivered_amount: device_data.cumulative_amount, completed };
+                        if let Err(e) = binding::unbind(device_data.device_id, cause).await {
+                            error!("unbind device {} on power off failed: {}", device_data.device_id, e);
+                        }
+                    } else {
+                        let final_state = if completed {
+                            InfusionFinalState::Completed
+                        } else {
+                            InfusionFinalState::Stopped
+                        };
+                        match finish_infusion(device_data.device_id, Some(device_data.cumulative_amount), final_state).await {
+                            Ok(Some(infusion)) => {
+                                info!("infusion {:?} of device {} closed on power off", infusion.id, device_data.device_id);
+                                if completed {
+                                    drug_plan::on_completed(infusion.drug_id).await;
+                                }
+                            },
+                            Ok(None) => {},
+                            Err(e) => error!("finish infusion failed: {}", e),
+                        }
+                    }
+                }
                 if device_data.status == DeviceStatus::ING as u8 {
                     println!("收到设备输液消息{:?}", device_data);
-                    match update_patient_by_device_id(device_data).await {
-                        Ok(_) => {},
-                        Err(e) => error!("update patient data failed: {}", e),
+                    if infusion::is_complete(&device_data) {
+                        // The device keeps reporting ING until stopped, this must not open a new session.
+                        infusion::complete(&device_data, patient.as_ref()).await;
+                        move_patient(patient.as_ref(), PatientState::Complete, TransitionCause::InfusionComplete).await;
+                    } else {
+                        match start_infusion(&device_data).await {
+                            Ok(Some(session)) => {
+                                if let Some(patient) = patient.as_ref() {
+                                    drug_plan::on_started(patient, session.drug_id).await;
+                                }
+                                if let Err(e) = update_infusion_progress(&device_data).await {
+                                    error!("update infusion progress failed: {}", e);
+                                }
+                                let estimate = infusion::track_rate(&device_data, session.drops_per_ml);
+                                live::broadcast(LiveEvent::InfusionEstimate(estimate), patient_no.clone(), bed_no.clone());
+                            },
+                            Ok(None) => info!("no patient is bound to device {}", device_data.device_id),
+                            Err(e) => error!("start infusion failed: {}", e),
+                        }
+                        infusion::check_progress(&device_data, patient.as_ref()).await;
+                        move_patient(patient.as_ref(), PatientState::Infusing, TransitionCause::DeviceInfusing).await;
+                    }
+                    if let Err(e) = update_patient_by_device_id(device_data).await {
+                        error!("update patient data failed: {}", e);
                     }
-
-                    // if device_data.sos_state == 115 {
-                        //use websocket to send sos message
-                    // }
                 }
             },
             Err(e) => {
@@ -88,3 +234,78 @@ impl AsyncConsumer for DeviceDataConsumer {
     }
 }
 
+/// Moves the patient bound to the device, if any. Completion is only recorded for a patient that
+/// was actually infusing, so frames of an already finished bag do not try to move it again.
+async fn move_patient(patient: Option<&Patient>, to: PatientState, cause: TransitionCause) {
+    let Some(patient) = patient else {
+        return;
+    };
+
+    let from = PatientState::from_status(patient.status);
+    if to == PatientState::Complete && !matches!(from, Some(PatientState::Infusing | PatientState::Paused)) {
+        return;
+    }
+
+    if let Err(e) = patient_state::transition(patient, to, cause).await {
+        warn!("patient {} not moved to {:?}: {}", patient.patient_no, to, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::checksum;
+
+    /// A `device_data` frame of device 5 infusing at 42 drops/min, 300 of 500 delivered.
+    fn infusing_frame() -> Vec<u8> {
+        let mut bytes = vec![0u8; DEVICE_DATA_FRAME_LEN];
+        bytes[..3].copy_from_slice(&[0xFD, 0xDD, 0x05]);
+        bytes[12] = 42;
+        bytes[13..15].copy_from_slice(&500u16.to_be_bytes());
+        bytes[15..17].copy_from_slice(&300u16.to_be_bytes());
+        bytes[17] = 2;
+        bytes[18] = 36;
+        bytes[22] = 17;
+        bytes[23] = 80;
+        bytes[26] = SOS_PRESSED;
+        bytes[27] = checksum(&bytes[..27]);
+        bytes
+    }
+
+    #[test]
+    fn reads_fields_at_their_wire_offsets() {
+        let data = DeviceData::decode(&infusing_frame(), 0).unwrap();
+
+        assert_eq!(data.device_id, 5);
+        assert_eq!(data.drip_value, 42);
+        assert_eq!(data.preset_amount, 500);
+        assert_eq!(data.cumulative_amount, 300);
+        assert_eq!(data.tem_gear_value, 2);
+        assert_eq!(data.tem_value, 36);
+        assert!(data.is_infusing());
+        assert_eq!(data.power_state, 80);
+        assert!(data.is_sos());
+    }
+
+    #[test]
+    fn rejects_frames_of_another_length() {
+        let mut bytes = infusing_frame();
+        bytes.push(0);
+        assert_eq!(
+            DeviceData::decode(&bytes, 0).unwrap_err(),
+            FrameError::LengthMismatch { expected: DEVICE_DATA_FRAME_LEN, actual: DEVICE_DATA_FRAME_LEN + 1 },
+        );
+    }
+
+    #[test]
+    fn only_the_configured_code_means_power_off() {
+        let mut bytes = infusing_frame();
+        bytes[22] = 0xAA;
+        bytes[27] = checksum(&bytes[..27]);
+
+        assert!(DeviceData::decode(&bytes, 0xAA).unwrap().is_off());
+        let data = DeviceData::decode(&bytes, 0).unwrap();
+        assert!(data.is_unknown());
+        assert!(!data.is_off());
+    }
+}
@@ -3,6 +3,6 @@ mod binding;
 mod publisher;
 mod device_data_consumer;
 
-pub use amqp::init_mq;
+pub use amqp::{get_mq_status, init_mq};
 pub use publisher::*;
 pub use device_data_consumer::*;
@@ -0,0 +1,205 @@
+use std::fmt;
+use tracing::info;
+
+use crate::live::{self, LiveEvent};
+use crate::repository::{apply_patient_transition, Patient, PatientTransition};
+
+/// Infusion state of a patient, stored as `patient.status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatientState {
+    Unbound = 0,
+    Bound = 1,
+    Infusing = 2,
+    Complete = 3,
+    Paused = 4,
+    Waiting = 5,
+    Released = 6,
+}
+
+impl PatientState {
+    pub fn from_status(status: Option<u16>) -> Option<Self> {
+        match status.unwrap_or(0) {
+            0 => Some(PatientState::Unbound),
+            1 => Some(PatientState::Bound),
+            2 => Some(PatientState::Infusing),
+            3 => Some(PatientState::Complete),
+            4 => Some(PatientState::Paused),
+            5 => Some(PatientState::Waiting),
+            6 => Some(PatientState::Released),
+            _ => None,
+        }
+    }
+
+    pub fn can_transition_to(self, to: PatientState) -> bool {
+        use PatientState::*;
+
+        match (self, to) {
+            (Unbound, Bound) => true,
+            (Bound, Infusing | Waiting | Complete) => true,
+            (Waiting, Infusing | Bound) => true,
+            (Infusing, Paused | Complete) => true,
+            (Paused, Infusing | Complete) => true,
+            // The next bag of the same patient.
+            (Complete, Infusing | Released) => true,
+            (Released, Bound) => true,
+            // A device can be taken off the patient at any point.
+            (from, Unbound) => from != Unbound,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionCause {
+    Bind,
+    Unbind,
+    PowerOff,
+    DeviceInfusing,
+    DeviceStopped,
+    InfusionComplete,
+}
+
+impl TransitionCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransitionCause::Bind => "bind",
+            TransitionCause::Unbind => "unbind",
+            TransitionCause::PowerOff => "power_off",
+            TransitionCause::DeviceInfusing => "device_infusing",
+            TransitionCause::DeviceStopped => "device_stopped",
+            TransitionCause::InfusionComplete => "infusion_complete",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    UnknownState { status: u16 },
+    Illegal { from: PatientState, to: PatientState },
+    Conflict,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::UnknownState { status } => write!(f, "unknown patient status {}", status),
+            TransitionError::Illegal { from, to } => write!(f, "illegal transition from {:?} to {:?}", from, to),
+            TransitionError::Conflict => write!(f, "patient status changed concurrently"),
+            TransitionError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl From<sqlx::Error> for TransitionError {
+    fn from(e: sqlx::Error) -> Self {
+        TransitionError::Database(e)
+    }
+}
+
+/// Checks a move without applying it, for callers that must validate before other writes.
+pub fn validate(patient: &Patient, to: PatientState) -> Result<PatientState, TransitionError> {
+    let from = PatientState::from_status(patient.status)
+        .ok_or(TransitionError::UnknownState { status: patient.status.unwrap_or_default() })?;
+
+    if from != to && !from.can_transition_to(to) {
+        return Err(TransitionError::Illegal { from, to });
+    }
+
+    Ok(from)
+}
+
+/// The only way `patient.status` changes: validates the move, records it and pushes it to the
+/// live feed. Moving to the current state is a no-op and returns `None`.
+pub async fn transition(patient: &Patient, to: PatientState, cause: TransitionCause) -> Result<Option<PatientTransition>, TransitionError> {
+    let from = validate(patient, to)?;
+    if from == to {
+        return Ok(None);
+    }
+
+    let transition = apply_patient_transition(&patient.patient_no, patient.status, to as u16, cause.as_str())
+        .await?
+        .ok_or(TransitionError::Conflict)?;
+
+    announce(patient, &transition);
+
+    Ok(Some(transition))
+}
+
+/// Logs a recorded move and pushes it to the live feed, also for moves written by other
+/// transactions such as `apply_unbinding`.
+pub fn announce(patient: &Patient, transition: &PatientTransition) {
+    let from = PatientState::from_status(transition.from_state);
+    let to = PatientState::from_status(Some(transition.to_state));
+    info!("patient {} moved from {:?} to {:?} by {}", patient.patient_no, from, to, transition.cause);
+    live::broadcast(
+        LiveEvent::PatientStatus { from: transition.from_state, to: transition.to_state, cause: transition.cause.clone() },
+        Some(patient.patient_no.clone()),
+        Some(patient.bed_no.clone()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PatientState::*;
+
+    const ALL: [PatientState; 7] = [Unbound, Bound, Infusing, Complete, Paused, Waiting, Released];
+
+    fn patient(status: Option<u16>) -> Patient {
+        let mut patient = Patient::new("P001".to_string(), "张三".to_string(), "男".to_string(), 60, "01".to_string(), None);
+        patient.status = status;
+        patient
+    }
+
+    #[test]
+    fn follows_the_infusion_cycle() {
+        assert!(Unbound.can_transition_to(Bound));
+        assert!(Bound.can_transition_to(Waiting));
+        assert!(Waiting.can_transition_to(Infusing));
+        assert!(Infusing.can_transition_to(Paused));
+        assert!(Paused.can_transition_to(Infusing));
+        assert!(Infusing.can_transition_to(Complete));
+        assert!(Complete.can_transition_to(Infusing));
+        assert!(Complete.can_transition_to(Released));
+        assert!(Released.can_transition_to(Bound));
+    }
+
+    #[test]
+    fn any_bound_state_can_be_unbound() {
+        for from in ALL {
+            assert_eq!(from.can_transition_to(Unbound), from != Unbound, "{:?} -> Unbound", from);
+        }
+    }
+
+    #[test]
+    fn rejects_skipping_steps() {
+        assert!(!Unbound.can_transition_to(Infusing));
+        assert!(!Unbound.can_transition_to(Complete));
+        assert!(!Waiting.can_transition_to(Complete));
+        assert!(!Infusing.can_transition_to(Bound));
+        assert!(!Infusing.can_transition_to(Released));
+        assert!(!Paused.can_transition_to(Bound));
+        assert!(!Complete.can_transition_to(Bound));
+        assert!(!Released.can_transition_to(Infusing));
+    }
+
+    #[test]
+    fn reads_stored_statuses() {
+        for state in ALL {
+            assert_eq!(PatientState::from_status(Some(state as u16)), Some(state));
+        }
+        assert_eq!(PatientState::from_status(None), Some(Unbound));
+        assert_eq!(PatientState::from_status(Some(7)), None);
+    }
+
+    #[test]
+    fn validate_allows_staying_put_and_rejects_illegal_moves() {
+        assert_eq!(validate(&patient(Some(2)), Infusing).unwrap(), Infusing);
+        assert_eq!(validate(&patient(None), Bound).unwrap(), Unbound);
+        assert!(matches!(validate(&patient(Some(0)), Infusing), Err(TransitionError::Illegal { from: Unbound, to: Infusing })));
+        assert!(matches!(validate(&patient(Some(9)), Bound), Err(TransitionError::UnknownState { status: 9 })));
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Every frame exchanged with the pumps, in both directions, is laid out as
+/// `[0xFD, 0xDD, device_id, body.., xor]` where `xor` folds all preceding bytes.
+pub const FRAME_HEADER: [u8; 2] = [0xFD, 0xDD];
+pub const MIN_FRAME_LEN: usize = FRAME_HEADER.len() + 2;
+pub const DEVICE_DATA_FRAME_LEN: usize = 28;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    TooShort { len: usize },
+    BadHeader { header: [u8; 2] },
+    InvalidDeviceId { device_id: u8 },
+    LengthMismatch { expected: usize, actual: usize },
+    BadChecksum { expected: u8, actual: u8 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooShort { len } => write!(f, "frame is too short: {} bytes", len),
+            FrameError::BadHeader { header } => write!(f, "bad frame header: {:02X} {:02X}", header[0], header[1]),
+            FrameError::InvalidDeviceId { device_id } => write!(f, "invalid device id: {}", device_id),
+            FrameError::LengthMismatch { expected, actual } => write!(f, "frame length mismatch: expected {} bytes, got {}", expected, actual),
+            FrameError::BadChecksum { expected, actual } => write!(f, "bad frame checksum: expected {:02X}, got {:02X}", expected, actual),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub device_id: u8,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(device_id: u8, body: Vec<u8>) -> Self {
+        Self { device_id, body }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.extend_from_slice(&FRAME_HEADER);
+        bytes.push(self.device_id);
+        bytes.extend_from_slice(&self.body);
+        bytes.push(checksum(&bytes));
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+        if bytes.len() < MIN_FRAME_LEN {
+            return Err(FrameError::TooShort { len: bytes.len() });
+        }
+
+        if bytes[..2] != FRAME_HEADER {
+            return Err(FrameError::BadHeader { header: [bytes[0], bytes[1]] });
+        }
+
+        let device_id = bytes[2];
+        if device_id == 0 {
+            return Err(FrameError::InvalidDeviceId { device_id });
+        }
+
+        let (content, xor) = bytes.split_at(bytes.len() - 1);
+        let expected = checksum(content);
+        if xor[0] != expected {
+            return Err(FrameError::BadChecksum { expected, actual: xor[0] });
+        }
+
+        Ok(Self { device_id, body: content[3..].to_vec() })
+    }
+
+    /// Decodes a frame that must be exactly `expected` bytes long on the wire.
+    pub fn decode_exact(bytes: &[u8], expected: usize) -> Result<Self, FrameError> {
+        if bytes.len() != expected {
+            return Err(FrameError::LengthMismatch { expected, actual: bytes.len() });
+        }
+
+        Self::decode(bytes)
+    }
+
+    /// Length of the encoded frame including header and checksum.
+    pub fn encoded_len(&self) -> usize {
+        FRAME_HEADER.len() + 1 + self.body.len() + 1
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |acc, x| acc ^ x)
+}
+
+const TURN_OFF: u8 = 0x7C;
+const START_DRIP: u8 = 0xEA;
+const STOP_DRIP: u8 = 0xEF;
+/// Settings are written as `[SETTING, register, data length (2 bytes), data (2 bytes)]`.
+const SETTING: u8 = 0xFE;
+const SETTING_DATA_LEN: [u8; 2] = [0x00, 0x02];
+const REGISTER_DRIP_RATE: u8 = 0x04;
+const REGISTER_PRESET_AMOUNT: u8 = 0x08;
+
+/// Commands published on `controll_device`. A new command only needs a variant here and its body in `body()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum DeviceCommand {
+    TurnOff { device_id: u8 },
+    StartDrip { device_id: u8 },
+    StopDrip { device_id: u8 },
+    SetDripRate { device_id: u8, drip_rate: u8 },
+    ModifyPresetAmount { device_id: u8, preset_amount: u16 },
+    /// The register is not part of the known device protocol yet and comes from
+    /// `device.tem_gear_register` once confirmed against the device documentation.
+    SetTemGear { device_id: u8, register: u8, tem_gear: u8 },
+}
+
+impl DeviceCommand {
+    pub fn device_id(&self) -> u8 {
+        match *self {
+            DeviceCommand::TurnOff { device_id }
+            | DeviceCommand::StartDrip { device_id }
+            | DeviceCommand::StopDrip { device_id }
+            | DeviceCommand::SetDripRate { device_id, .. }
+            | DeviceCommand::ModifyPresetAmount { device_id, .. }
+            | DeviceCommand::SetTemGear { device_id, .. } => device_id,
+        }
+    }
+
+    fn body(&self) -> Vec<u8> {
+        match *self {
+            DeviceCommand::TurnOff { .. } => vec![TURN_OFF],
+            DeviceCommand::StartDrip { .. } => vec![START_DRIP],
+            DeviceCommand::StopDrip { .. } => vec![STOP_DRIP],
+            DeviceCommand::SetDripRate { drip_rate, .. } => setting(REGISTER_DRIP_RATE, [0x00, drip_rate]),
+            DeviceCommand::ModifyPresetAmount { preset_amount, .. } => setting(REGISTER_PRESET_AMOUNT, preset_amount.to_be_bytes()),
+            DeviceCommand::SetTemGear { register, tem_gear, .. } => setting(register, [0x00, tem_gear]),
+        }
+    }
+
+    /// The checksummed frame ready to be published.
+    pub fn encode(&self) -> Vec<u8> {
+        Frame::new(self.device_id(), self.body()).encode()
+    }
+}
+
+fn setting(register: u8, data: [u8; 2]) -> Vec<u8> {
+    let mut body = vec![SETTING, register];
+    body.extend_from_slice(&SETTING_DATA_LEN);
+    body.extend_from_slice(&data);
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(device_id: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xFD, 0xDD, device_id];
+        bytes.extend_from_slice(body);
+        bytes.push(checksum(&bytes));
+        bytes
+    }
+
+    #[test]
+    fn encodes_run_state_commands() {
+        assert_eq!(DeviceCommand::TurnOff { device_id: 1 }.encode(), vec![0xFD, 0xDD, 0x01, 0x7C, 0x5D]);
+        assert_eq!(DeviceCommand::StartDrip { device_id: 1 }.encode(), vec![0xFD, 0xDD, 0x01, 0xEA, 0xCB]);
+        assert_eq!(DeviceCommand::StopDrip { device_id: 1 }.encode(), vec![0xFD, 0xDD, 0x01, 0xEF, 0xCE]);
+    }
+
+    #[test]
+    fn encodes_setting_commands() {
+        assert_eq!(
+            DeviceCommand::SetDripRate { device_id: 3, drip_rate: 60 }.encode(),
+            vec![0xFD, 0xDD, 0x03, 0xFE, 0x04, 0x00, 0x02, 0x00, 0x3C, 0xE7],
+        );
+        assert_eq!(
+            DeviceCommand::ModifyPresetAmount { device_id: 3, preset_amount: 500 }.encode(),
+            vec![0xFD, 0xDD, 0x03, 0xFE, 0x08, 0x00, 0x02, 0x01, 0xF4, 0x22],
+        );
+        assert_eq!(
+            DeviceCommand::SetTemGear { device_id: 3, register: 0x0C, tem_gear: 2 }.encode(),
+            vec![0xFD, 0xDD, 0x03, 0xFE, 0x0C, 0x00, 0x02, 0x00, 0x02, 0xD1],
+        );
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let frame = Frame::new(7, vec![0x01, 0x02, 0x03]);
+        assert_eq!(Frame::decode(&frame.encode()), Ok(frame));
+    }
+
+    #[test]
+    fn rejects_short_frames() {
+        assert_eq!(Frame::decode(&[0xFD, 0xDD, 0x01]), Err(FrameError::TooShort { len: 3 }));
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let mut bytes = framed(1, &[0x7C]);
+        bytes[1] = 0xDE;
+        assert_eq!(Frame::decode(&bytes), Err(FrameError::BadHeader { header: [0xFD, 0xDE] }));
+    }
+
+    #[test]
+    fn rejects_device_id_zero() {
+        assert_eq!(Frame::decode(&framed(0, &[0x7C])), Err(FrameError::InvalidDeviceId { device_id: 0 }));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut bytes = framed(1, &[0x7C]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        assert_eq!(Frame::decode(&bytes), Err(FrameError::BadChecksum { expected: 0x5D, actual: 0xA2 }));
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let bytes = framed(1, &[0x7C]);
+        assert_eq!(
+            Frame::decode_exact(&bytes, DEVICE_DATA_FRAME_LEN),
+            Err(FrameError::LengthMismatch { expected: DEVICE_DATA_FRAME_LEN, actual: bytes.len() }),
+        );
+    }
+}
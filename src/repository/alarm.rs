@@ -0,0 +1,299 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use crate::db::get_db;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AlarmRecord {
+    pub id: i64,
+    pub alarm_type: String,
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
+    pub patient_name: Option<String>,
+    pub bed_no: Option<String>,
+    pub message: Option<String>,
+    pub severity: u8, //1: 低，2：中，3：高
+    pub state: u8, //0: 报警中，1：已确认，2：已解除
+    pub raised_at: NaiveDateTime,
+    pub acknowledged_at: Option<NaiveDateTime>,
+    pub acknowledged_by: Option<String>,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub resolved_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmState {
+    Raised = 0,
+    Acknowledged = 1,
+    Resolved = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmType {
+    Unbound,
+    Sos,
+    InfusionComplete,
+    NearEmpty,
+    Occlusion,
+    LowBattery,
+    TemperatureOutOfRange,
+    DeviceOffline,
+    DrugLimitExceeded,
+    DripRateDeviation,
+}
+
+impl AlarmType {
+    pub const ALL: [AlarmType; 10] = [
+        AlarmType::Unbound,
+        AlarmType::Sos,
+        AlarmType::InfusionComplete,
+        AlarmType::NearEmpty,
+        AlarmType::Occlusion,
+        AlarmType::LowBattery,
+        AlarmType::TemperatureOutOfRange,
+        AlarmType::DeviceOffline,
+        AlarmType::DrugLimitExceeded,
+        AlarmType::DripRateDeviation,
+    ];
+
+    /// Parses the `alarm.alarm_type` column.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlarmType::Unbound => "unbound",
+            AlarmType::Sos => "sos",
+            AlarmType::InfusionComplete => "infusion_complete",
+            AlarmType::NearEmpty => "near_empty",
+            AlarmType::Occlusion => "occlusion",
+            AlarmType::LowBattery => "low_battery",
+            AlarmType::TemperatureOutOfRange => "temperature_out_of_range",
+            AlarmType::DeviceOffline => "device_offline",
+            AlarmType::DrugLimitExceeded => "drug_limit_exceeded",
+            AlarmType::DripRateDeviation => "drip_rate_deviation",
+        }
+    }
+
+    pub fn severity(&self) -> AlarmSeverity {
+        match self {
+            AlarmType::Unbound => AlarmSeverity::Low,
+            AlarmType::InfusionComplete | AlarmType::NearEmpty | AlarmType::LowBattery | AlarmType::TemperatureOutOfRange
+            | AlarmType::DripRateDeviation => AlarmSeverity::Medium,
+            AlarmType::Sos | AlarmType::Occlusion | AlarmType::DeviceOffline | AlarmType::DrugLimitExceeded => AlarmSeverity::High,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AlarmSeverity {
+    Low = 1,
+    Medium = 2,
+    High = 3,
+}
+
+#[derive(Debug)]
+pub struct NewAlarm {
+    pub alarm_type: AlarmType,
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
+    pub patient_name: Option<String>,
+    pub bed_no: Option<String>,
+    pub message: Option<String>,
+}
+
+pub async fn insert_alarm(alarm: NewAlarm) -> Result<AlarmRecord, sqlx::Error> {
+    let db = get_db();
+
+    let id = sqlx::query("INSERT INTO alarm (alarm_type, device_id, patient_no, patient_name, bed_no, message, severity, state, raised_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(alarm.alarm_type.as_str())
+        .bind(alarm.device_id)
+        .bind(alarm.patient_no)
+        .bind(alarm.patient_name)
+        .bind(alarm.bed_no)
+        .bind(alarm.message)
+        .bind(alarm.alarm_type.severity() as u8)
+        .bind(AlarmState::Raised as u8)
+        .bind(Local::now().naive_local())
+        .execute(db.as_ref())
+        .await?
+        .last_insert_rowid();
+
+    fetch_alarm_by_id(id).await?.ok_or(sqlx::Error::RowNotFound)
+}
+
+pub async fn fetch_alarm_by_id(id: i64) -> Result<Option<AlarmRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let alarm = sqlx::query_as::<_, AlarmRecord>("SELECT * FROM alarm WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(alarm)
+}
+
+/// The latest alarm of the given type for the device that nobody has acknowledged yet.
+pub async fn fetch_raised_alarm(alarm_type: AlarmType, device_id: u8) -> Result<Option<AlarmRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let alarm = sqlx::query_as::<_, AlarmRecord>("SELECT * FROM alarm WHERE alarm_type = ? AND device_id = ? AND state = ? ORDER BY id DESC LIMIT 1")
+        .bind(alarm_type.as_str())
+        .bind(device_id)
+        .bind(AlarmState::Raised as u8)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(alarm)
+}
+
+/// The latest alarm of the given type for the device that has not been resolved yet.
+pub async fn fetch_open_alarm(alarm_type: AlarmType, device_id: u8) -> Result<Option<AlarmRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let alarm = sqlx::query_as::<_, AlarmRecord>("SELECT * FROM alarm WHERE alarm_type = ? AND device_id = ? AND state != ? ORDER BY id DESC LIMIT 1")
+        .bind(alarm_type.as_str())
+        .bind(device_id)
+        .bind(AlarmState::Resolved as u8)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(alarm)
+}
+
+pub async fn query_open_alarms(alarm_type: Option<String>) -> Result<Vec<AlarmRecord>, sqlx::Error> {
+    query_alarms(AlarmQuery { alarm_type, active_only: true, ..Default::default() }).await
+}
+
+#[derive(Debug, Default)]
+pub struct AlarmQuery {
+    pub alarm_type: Option<String>,
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
+    pub state: Option<u8>,
+    pub active_only: bool,
+    pub page: Option<(u16, u16)>,
+}
+
+pub async fn query_alarms(filter: AlarmQuery) -> Result<Vec<AlarmRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let mut query = String::from("SELECT * FROM alarm WHERE 1=1");
+
+    if filter.active_only {
+        query.push_str(" AND state != ?");
+    }
+
+    if filter.alarm_type.is_some() {
+        query.push_str(" AND alarm_type = ?");
+    }
+
+    if filter.device_id.is_some() {
+        query.push_str(" AND device_id = ?");
+    }
+
+    if filter.patient_no.is_some() {
+        query.push_str(" AND patient_no = ?");
+    }
+
+    if filter.state.is_some() {
+        query.push_str(" AND state = ?");
+    }
+
+    query.push_str(" ORDER BY severity DESC, raised_at DESC");
+
+    if filter.page.is_some() {
+        query.push_str(" LIMIT ? OFFSET ?");
+    }
+
+    let mut query_builder = sqlx::query_as::<_, AlarmRecord>(&query);
+
+    if filter.active_only {
+        query_builder = query_builder.bind(AlarmState::Resolved as u8);
+    }
+
+    if let Some(t) = filter.alarm_type {
+        query_builder = query_builder.bind(t);
+    }
+
+    if let Some(d) = filter.device_id {
+        query_builder = query_builder.bind(d);
+    }
+
+    if let Some(p) = filter.patient_no {
+        query_builder = query_builder.bind(p);
+    }
+
+    if let Some(s) = filter.state {
+        query_builder = query_builder.bind(s);
+    }
+
+    if let Some((page, page_size)) = filter.page {
+        query_builder = query_builder.bind(page_size).bind((page.max(1) as i64 - 1) * page_size as i64);
+    }
+
+    query_builder.fetch_all(db.as_ref()).await
+}
+
+/// Returns the updated alarm, or `None` when it does not exist or is already past that state.
+pub async fn acknowledge_alarm(id: i64, nurse: String) -> Result<Option<AlarmRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let result = sqlx::query("UPDATE alarm SET state = ?, acknowledged_at = ?, acknowledged_by = ? WHERE id = ? AND state = ?")
+        .bind(AlarmState::Acknowledged as u8)
+        .bind(Local::now().naive_local())
+        .bind(nurse)
+        .bind(id)
+        .bind(AlarmState::Raised as u8)
+        .execute(db.as_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    fetch_alarm_by_id(id).await
+}
+
+pub async fn resolve_alarm(id: i64, nurse: Option<String>) -> Result<Option<AlarmRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let result = sqlx::query("UPDATE alarm SET state = ?, resolved_at = ?, resolved_by = ? WHERE id = ? AND state != ?")
+        .bind(AlarmState::Resolved as u8)
+        .bind(Local::now().naive_local())
+        .bind(nurse)
+        .bind(id)
+        .bind(AlarmState::Resolved as u8)
+        .execute(db.as_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    fetch_alarm_by_id(id).await
+}
+
+/// Resolves every open alarm of the given type for the device, e.g. once the condition behind it clears.
+pub async fn resolve_open_alarms(alarm_type: AlarmType, device_id: u8, resolved_by: &str) -> Result<Vec<AlarmRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let open = sqlx::query_as::<_, AlarmRecord>("SELECT * FROM alarm WHERE alarm_type = ? AND device_id = ? AND state != ?")
+        .bind(alarm_type.as_str())
+        .bind(device_id)
+        .bind(AlarmState::Resolved as u8)
+        .fetch_all(db.as_ref())
+        .await?;
+
+    let mut resolved = Vec::with_capacity(open.len());
+    for alarm in open {
+        if let Some(alarm) = resolve_alarm(alarm.id, Some(resolved_by.to_string())).await? {
+            resolved.push(alarm);
+        }
+    }
+
+    Ok(resolved)
+}
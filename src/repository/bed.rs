@@ -25,7 +25,7 @@ pub async fn query_bed() -> Result<Vec<Bed>, sqlx::Error> {
     Ok(beds)
 }
 
-pub async fn insert_beds(beds: Vec<Bed>) -> Result<(), sqlx::Error> {
+pub async fn upsert_beds(beds: Vec<Bed>) -> Result<(), sqlx::Error> {
     if beds.is_empty() {
         return Ok(());
     }
@@ -33,7 +33,7 @@ pub async fn insert_beds(beds: Vec<Bed>) -> Result<(), sqlx::Error> {
     let mut tx = get_db().begin().await?;
 
     for bed in beds {
-        sqlx::query("INSERT INTO bed (bed_no, mac) VALUES (?, ?)")
+        sqlx::query("INSERT INTO bed (bed_no, mac) VALUES (?, ?) ON CONFLICT (bed_no) DO UPDATE SET mac = excluded.mac")
         .bind(bed.bed_no.clone())
         .bind(bed.mac.clone())
         .execute(&mut *tx)
@@ -0,0 +1,87 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use crate::db::get_db;
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct BindingAttempt {
+    pub id: i64,
+    pub source: String, //mq, api
+    pub action: String, //bind, unbind
+    pub device_mac: Option<String>,
+    pub bed_mac: Option<String>,
+    pub device_id: Option<u8>,
+    pub bed_no: Option<String>,
+    pub patient_no: Option<String>,
+    pub success: bool,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub struct NewBindingAttempt {
+    pub source: &'static str,
+    pub action: &'static str,
+    pub device_mac: Option<String>,
+    pub bed_mac: Option<String>,
+    pub device_id: Option<u8>,
+    pub bed_no: Option<String>,
+    pub patient_no: Option<String>,
+    pub success: bool,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
+pub async fn insert_binding_attempt(attempt: NewBindingAttempt) -> Result<i64, sqlx::Error> {
+    let db = get_db();
+
+    let id = sqlx::query("INSERT INTO binding_attempt (source, action, device_mac, bed_mac, device_id, bed_no, patient_no, success, reason, message, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(attempt.source)
+        .bind(attempt.action)
+        .bind(attempt.device_mac)
+        .bind(attempt.bed_mac)
+        .bind(attempt.device_id)
+        .bind(attempt.bed_no)
+        .bind(attempt.patient_no)
+        .bind(attempt.success)
+        .bind(attempt.reason)
+        .bind(attempt.message)
+        .bind(Local::now().naive_local())
+        .execute(db.as_ref())
+        .await?
+        .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn query_binding_attempts(page: u16, page_size: u16, device_id: Option<u8>, success: Option<bool>) -> Result<Vec<BindingAttempt>, sqlx::Error> {
+    let db = get_db();
+
+    let mut query = String::from("SELECT * FROM binding_attempt WHERE 1=1");
+
+    if device_id.is_some() {
+        query.push_str(" AND device_id = ?");
+    }
+
+    if success.is_some() {
+        query.push_str(" AND success = ?");
+    }
+
+    query.push_str(" ORDER BY id DESC LIMIT ? OFFSET ?");
+
+    let mut query_builder = sqlx::query_as::<_, BindingAttempt>(&query);
+
+    if let Some(d) = device_id {
+        query_builder = query_builder.bind(d);
+    }
+
+    if let Some(s) = success {
+        query_builder = query_builder.bind(s);
+    }
+
+    query_builder = query_builder.bind(page_size).bind((page.max(1) as i64 - 1) * page_size as i64);
+
+    query_builder.fetch_all(db.as_ref()).await
+}
@@ -0,0 +1,95 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use crate::db::get_db;
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandRecord {
+    pub id: i64,
+    pub device_id: u8,
+    pub command: String,
+    pub state: u8, //0: 待确认，1：已确认，2：失败
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandState {
+    Pending = 0,
+    Confirmed = 1,
+    Failed = 2,
+}
+
+pub async fn insert_command(device_id: u8, command: String) -> Result<i64, sqlx::Error> {
+    let db = get_db();
+    let now = Local::now().naive_local();
+
+    let id = sqlx::query("INSERT INTO device_command (device_id, command, state, attempts, created_at, updated_at) VALUES (?, ?, ?, 0, ?, ?)")
+        .bind(device_id)
+        .bind(command)
+        .bind(CommandState::Pending as u8)
+        .bind(now)
+        .bind(now)
+        .execute(db.as_ref())
+        .await?
+        .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn update_command_attempt(id: i64, attempts: u32, last_error: Option<String>) -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query("UPDATE device_command SET attempts = ?, last_error = ?, updated_at = ? WHERE id = ?")
+        .bind(attempts)
+        .bind(last_error)
+        .bind(Local::now().naive_local())
+        .bind(id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(())
+}
+
+pub async fn update_command_state(id: i64, state: CommandState, last_error: Option<String>) -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query("UPDATE device_command SET state = ?, last_error = COALESCE(?, last_error), updated_at = ? WHERE id = ?")
+        .bind(state as u8)
+        .bind(last_error)
+        .bind(Local::now().naive_local())
+        .bind(id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(())
+}
+
+/// Commands left pending by a previous run can no longer be confirmed.
+pub async fn fail_pending_commands(reason: &str) -> Result<u64, sqlx::Error> {
+    let db = get_db();
+
+    let result = sqlx::query("UPDATE device_command SET state = ?, last_error = ?, updated_at = ? WHERE state = ?")
+        .bind(CommandState::Failed as u8)
+        .bind(reason)
+        .bind(Local::now().naive_local())
+        .bind(CommandState::Pending as u8)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn fetch_command_by_id(id: i64) -> Result<Option<CommandRecord>, sqlx::Error> {
+    let db = get_db();
+
+    let command = sqlx::query_as::<_, CommandRecord>("SELECT * FROM device_command WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(command)
+}
@@ -1,6 +1,7 @@
+use chrono::{Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use sqlx::{prelude::FromRow, QueryBuilder, Sqlite};
-use crate::{db::get_db, mq::{publish_alarm, Alarm}};
+use crate::db::get_db;
 
 use super::Patient;
 
@@ -18,16 +19,22 @@ pub struct Device {
     tem_gear_value: Option<u8>,
     power_state: Option<u8>,
     pub do_bind: Option<u8>,
+    pub last_seen_at: Option<NaiveDateTime>,
+    pub online: Option<u8>, //0: 离线，1：在线
 }
 
 impl Device {
     pub fn new(device_id: u8, mac: Option<String>) -> Self {
-        Self { id: None, device_id, mac, status: None, drip_value: None, preset_amount: None, cumulative_amount: None, tem_value: None, tem_gear_value: None, power_state: None, do_bind: None  }
+        Self { id: None, device_id, mac, status: None, drip_value: None, preset_amount: None, cumulative_amount: None, tem_value: None, tem_gear_value: None, power_state: None, do_bind: None, last_seen_at: None, online: None }
     }
 
     pub fn get_status(&self) -> Option<u8> {
         self.status
     }
+
+    pub fn get_mac(&self) -> Option<String> {
+        self.mac.clone()
+    }
 }
 
 pub async fn query_device() -> Result<Vec<Device>, sqlx::Error> {
@@ -40,7 +47,8 @@ pub async fn query_device() -> Result<Vec<Device>, sqlx::Error> {
     Ok(devices)
 }
 
-pub async fn insert_devices(devices: Vec<Device>) -> Result<(), sqlx::Error> {
+/// Inserts new devices and refreshes the MAC of known ones without touching their runtime state.
+pub async fn upsert_devices(devices: Vec<Device>) -> Result<(), sqlx::Error> {
     if devices.is_empty() {
         return Ok(());
     }
@@ -48,7 +56,7 @@ pub async fn insert_devices(devices: Vec<Device>) -> Result<(), sqlx::Error> {
     let mut tx = get_db().begin().await?;
 
     for device in devices {
-        sqlx::query("INSERT INTO device (device_id, mac) VALUES (?, ?)")
+        sqlx::query("INSERT INTO device (device_id, mac) VALUES (?, ?) ON CONFLICT (device_id) DO UPDATE SET mac = excluded.mac")
         .bind(device.device_id)
         .bind(device.mac.clone())
         .execute(&mut *tx)
@@ -71,7 +79,22 @@ pub async fn is_device_exist(device_id: String) -> Result<bool, sqlx::Error> {
     Ok(device.is_some())
 }
 
-pub async fn update_device_status(device_id: u8, status: u8) -> Result<(), sqlx::Error> {
+/// Records that the device was switched off, so binding is refused until it powers on again and
+/// the next power-on without a patient raises the unbound alarm again.
+pub async fn set_device_powered_off(device_id: u8, status: u8) -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query("update device set status = ?, do_bind = 0 where device_id = ?")
+        .bind(status)
+        .bind(device_id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(())
+}
+
+/// Returns `true` the first time a powered-on device is found without a patient, so the caller raises the unbound alarm once.
+pub async fn update_device_status(device_id: u8, status: u8) -> Result<bool, sqlx::Error> {
     let db = get_db();
 
     let device = match sqlx::query_as::<_, Device>("select * from device where device_id = ?")
@@ -100,21 +123,73 @@ pub async fn update_device_status(device_id: u8, status: u8) -> Result<(), sqlx:
         .await?;
 
     if patient.is_none() && do_bind == 0 {
-        publish_alarm(Alarm::new(device_id, 0)).await;
         sqlx::query("update device set do_bind = ? where device_id = ?")
             .bind(1)
             .bind(device_id)
             .execute(db.as_ref())
             .await?;
+
+        return Ok(true);
     }
 
-    Ok(())
+    Ok(false)
+}
+
+/// Records that a frame arrived. Returns `true` when the device was considered offline before.
+pub async fn touch_device(device_id: u8) -> Result<bool, sqlx::Error> {
+    let db = get_db();
+
+    let was_offline = sqlx::query_scalar::<_, u8>("SELECT online FROM device WHERE device_id = ?")
+        .bind(device_id)
+        .fetch_optional(db.as_ref())
+        .await?
+        .is_some_and(|online| online == 0);
+
+    sqlx::query("UPDATE device SET last_seen_at = ?, online = 1 WHERE device_id = ?")
+        .bind(Local::now().naive_local())
+        .bind(device_id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(was_offline)
+}
+
+/// Marks online devices not heard from since `silent_since` as offline and powered off, returning them.
+pub async fn mark_silent_devices_offline(silent_since: NaiveDateTime) -> Result<Vec<Device>, sqlx::Error> {
+    let mut tx = get_db().begin().await?;
+
+    let devices = sqlx::query_as::<_, Device>("SELECT * FROM device WHERE online = 1 AND (last_seen_at IS NULL OR last_seen_at < ?)")
+        .bind(silent_since)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    for device in devices.iter() {
+        sqlx::query("UPDATE device SET online = 0, status = 0 WHERE device_id = ?")
+            .bind(device.device_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(devices)
+}
+
+pub async fn fetch_device_by_device_id(device_id: u8) -> Result<Option<Device>, sqlx::Error> {
+    let db = get_db();
+
+    let device = sqlx::query_as::<_, Device>("SELECT * FROM device WHERE device_id = ? limit 1")
+        .bind(device_id)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(device)
 }
 
 pub async fn fetch_device_by_device_mac(device_mac: String) -> Result<Option<Device>, sqlx::Error> {
     let db = get_db();
 
-    let device = sqlx::query_as::<_, Device>("SELECT * FROM device WHERE mac = ? AND status = 1 limit 1")
+    let device = sqlx::query_as::<_, Device>("SELECT * FROM device WHERE mac = ? limit 1")
         .bind(device_mac)
         .fetch_optional(db.as_ref())
         .await?;
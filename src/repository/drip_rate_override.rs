@@ -0,0 +1,99 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use crate::db::get_db;
+
+/// A drip rate sent outside the prescribed range with the reason given by the nurse.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct DripRateOverride {
+    pub id: i64,
+    pub device_id: u8,
+    pub patient_no: Option<String>,
+    pub drug_id: Option<i64>,
+    pub drug_name: Option<String>,
+    pub prescribed_rate: Option<u16>,
+    pub min_rate: u16,
+    pub max_rate: u16,
+    pub requested_rate: u8,
+    pub reason: String,
+    pub command_id: Option<i64>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub struct NewDripRateOverride {
+    pub device_id: u8,
+    pub patient_no: Option<String>,
+    pub drug_id: Option<i64>,
+    pub drug_name: Option<String>,
+    pub prescribed_rate: Option<u16>,
+    pub min_rate: u16,
+    pub max_rate: u16,
+    pub requested_rate: u8,
+    pub reason: String,
+}
+
+/// Records the override before its command is sent; the command is attached once dispatched.
+pub async fn insert_drip_rate_override(record: NewDripRateOverride) -> Result<i64, sqlx::Error> {
+    let db = get_db();
+
+    let id = sqlx::query("INSERT INTO drip_rate_override (device_id, patient_no, drug_id, drug_name, prescribed_rate, min_rate, max_rate, requested_rate, reason, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(record.device_id)
+        .bind(record.patient_no)
+        .bind(record.drug_id)
+        .bind(record.drug_name)
+        .bind(record.prescribed_rate)
+        .bind(record.min_rate)
+        .bind(record.max_rate)
+        .bind(record.requested_rate)
+        .bind(record.reason)
+        .bind(Local::now().naive_local())
+        .execute(db.as_ref())
+        .await?
+        .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn attach_drip_rate_override_command(id: i64, command_id: i64) -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query("UPDATE drip_rate_override SET command_id = ? WHERE id = ?")
+        .bind(command_id)
+        .bind(id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(())
+}
+
+pub async fn query_drip_rate_overrides(page: u16, page_size: u16, device_id: Option<u8>, patient_no: Option<String>) -> Result<Vec<DripRateOverride>, sqlx::Error> {
+    let db = get_db();
+
+    let mut query = String::from("SELECT * FROM drip_rate_override WHERE 1=1");
+
+    if device_id.is_some() {
+        query.push_str(" AND device_id = ?");
+    }
+
+    if patient_no.is_some() {
+        query.push_str(" AND patient_no = ?");
+    }
+
+    query.push_str(" ORDER BY id DESC LIMIT ? OFFSET ?");
+
+    let mut query_builder = sqlx::query_as::<_, DripRateOverride>(&query);
+
+    if let Some(d) = device_id {
+        query_builder = query_builder.bind(d);
+    }
+
+    if let Some(p) = patient_no {
+        query_builder = query_builder.bind(p);
+    }
+
+    query_builder = query_builder.bind(page_size).bind((page.max(1) as i64 - 1) * page_size as i64);
+
+    query_builder.fetch_all(db.as_ref()).await
+}
@@ -1,7 +1,10 @@
-use sqlx::{QueryBuilder, Sqlite, prelude::FromRow};
+use chrono::NaiveDateTime;
+use sqlx::prelude::FromRow;
 use serde::{Deserialize, Serialize};
 use crate::db::get_db;
 
+use super::Patient;
+
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Drug {
@@ -10,19 +13,34 @@ pub struct Drug {
     pub dosage: u16,
     pub drip_rate: u16,
     pub patient_no: Option<String>,
+    pub seq: u16, //在患者输液计划中的顺序
+    pub state: u8, //0: 待输液，1：输液中，2：已完成
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrugState {
+    Pending = 0,
+    Active = 1,
+    Completed = 2,
 }
 
 impl Drug {
     pub fn new(drug_name: String, dosage: u16, drip_rate: u16) -> Self {
-        Self { id: None, drug_name, dosage, drip_rate, patient_no: None }
+        Self { id: None, drug_name, dosage, drip_rate, patient_no: None, seq: 0, state: DrugState::Pending as u8 }
     }
 
     pub fn set_patient_no(&mut self, patient_no: String) {
         self.patient_no = Some(patient_no);
     }
+
+    pub fn set_seq(&mut self, seq: u16) {
+        self.seq = seq;
+    }
 }
 
-pub async fn insert_drugs(drugs: Vec<Drug>) -> Result<(), sqlx::Error> {
+/// Saves the synced plan keyed by position, so the same drug ordered twice keeps both entries.
+/// A position whose drug changed starts over as pending.
+pub async fn upsert_drugs(drugs: Vec<Drug>, synced_at: NaiveDateTime) -> Result<(), sqlx::Error> {
     if drugs.is_empty() {
         return Ok(());
     }
@@ -30,11 +48,22 @@ pub async fn insert_drugs(drugs: Vec<Drug>) -> Result<(), sqlx::Error> {
     let mut tx = get_db().begin().await?;
 
     for drug in drugs {
-        sqlx::query("INSERT INTO drug (drug_name, dosage, drip_rate, patient_no) VALUES (?, ?, ?, ?)")
+        sqlx::query(
+            "INSERT INTO drug (drug_name, dosage, drip_rate, patient_no, seq, synced_at) VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (patient_no, seq) DO UPDATE SET
+                state = CASE WHEN drug.drug_name = excluded.drug_name THEN drug.state ELSE ? END,
+                drug_name = excluded.drug_name,
+                dosage = excluded.dosage,
+                drip_rate = excluded.drip_rate,
+                synced_at = excluded.synced_at"
+        )
         .bind(drug.drug_name.clone())
         .bind(drug.dosage)
         .bind(drug.drip_rate)
         .bind(drug.patient_no.clone())
+        .bind(drug.seq)
+        .bind(synced_at)
+        .bind(DrugState::Pending as u8)
         .execute(&mut *tx)
         .await?;
     }
@@ -44,10 +73,93 @@ pub async fn insert_drugs(drugs: Vec<Drug>) -> Result<(), sqlx::Error> {
     Ok(())
 }
 
+/// Removes drugs that HIS no longer lists for a patient that was part of the sync started at `synced_at`.
+pub async fn delete_unsynced_drugs(synced_at: NaiveDateTime) -> Result<u64, sqlx::Error> {
+    let db = get_db();
+
+    let result = sqlx::query(
+        "DELETE FROM drug
+        WHERE (synced_at IS NULL OR synced_at < ?)
+        AND patient_no IN (SELECT patient_no FROM patient WHERE synced_at >= ?)"
+    )
+        .bind(synced_at)
+        .bind(synced_at)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// The drug the patient is currently on: `current_drug_id` unless that one is finished, otherwise
+/// the next unfinished drug of the plan, i.e. the bag about to be hung.
+pub async fn fetch_current_drug(patient: &Patient) -> Result<Option<Drug>, sqlx::Error> {
+    let db = get_db();
+
+    if let Some(drug_id) = patient.current_drug_id.as_ref().and_then(|id| id.parse::<i64>().ok()) {
+        let drug = sqlx::query_as::<_, Drug>("SELECT * FROM drug WHERE id = ? AND state != ?")
+            .bind(drug_id)
+            .bind(DrugState::Completed as u8)
+            .fetch_optional(db.as_ref())
+            .await?;
+
+        if drug.is_some() {
+            return Ok(drug);
+        }
+    }
+
+    fetch_next_drug(patient.patient_no.clone()).await
+}
+
+/// First drug of the plan that has not been infused yet.
+pub async fn fetch_next_drug(patient_no: String) -> Result<Option<Drug>, sqlx::Error> {
+    let db = get_db();
+
+    let drug = sqlx::query_as::<_, Drug>("SELECT * FROM drug WHERE patient_no = ? AND state = ? ORDER BY seq, id LIMIT 1")
+        .bind(patient_no)
+        .bind(DrugState::Pending as u8)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(drug)
+}
+
+/// Makes the drug the one hanging for the patient.
+pub async fn activate_drug(patient_no: String, drug_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = get_db().begin().await?;
+
+    sqlx::query("UPDATE patient SET current_drug_id = ? WHERE patient_no = ?")
+        .bind(drug_id.to_string())
+        .bind(patient_no)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("UPDATE drug SET state = ? WHERE id = ?")
+        .bind(DrugState::Active as u8)
+        .bind(drug_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn complete_drug(drug_id: i64) -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query("UPDATE drug SET state = ? WHERE id = ?")
+        .bind(DrugState::Completed as u8)
+        .bind(drug_id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(())
+}
+
 pub async fn query_drug_by_patient_no(patient_no: String) -> Result<Vec<Drug>, sqlx::Error> {
     let db = get_db();
 
-    let drugs = sqlx::query_as::<_, Drug>("SELECT * FROM drug WHERE patient_no = ?")
+    let drugs = sqlx::query_as::<_, Drug>("SELECT * FROM drug WHERE patient_no = ? ORDER BY seq, id")
         .bind(patient_no)
         .fetch_all(db.as_ref())
         .await?;
@@ -0,0 +1,112 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use crate::db::get_db;
+
+/// Locally managed limits of a drug, matched to the HIS prescriptions by code or name.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct DrugLibraryEntry {
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub drug_code: String,
+    pub drug_name: String,
+    pub concentration: Option<String>,
+    pub min_drip_rate: Option<u16>, //硬限制，不可超出
+    pub max_drip_rate: Option<u16>,
+    pub soft_min_drip_rate: Option<u16>, //软限制，超出需填写原因
+    pub soft_max_drip_rate: Option<u16>,
+    pub max_volume: Option<u16>,
+    pub tem_gear: Option<u8>,
+    pub min_temperature: Option<u8>,
+    pub max_temperature: Option<u8>,
+    #[serde(default)]
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+pub async fn query_drug_library(name: Option<String>) -> Result<Vec<DrugLibraryEntry>, sqlx::Error> {
+    let db = get_db();
+
+    let mut query = String::from("SELECT * FROM drug_library WHERE 1=1");
+
+    if name.is_some() {
+        query.push_str(" AND (drug_name LIKE ? OR drug_code LIKE ?)");
+    }
+
+    query.push_str(" ORDER BY drug_name");
+
+    let mut query_builder = sqlx::query_as::<_, DrugLibraryEntry>(&query);
+
+    if let Some(n) = name {
+        let pattern = format!("%{}%", n);
+        query_builder = query_builder.bind(pattern.clone()).bind(pattern);
+    }
+
+    query_builder.fetch_all(db.as_ref()).await
+}
+
+/// Looks a prescribed drug up in the library. Prescriptions from the HIS only carry the drug name,
+/// which is unique in the library.
+pub async fn fetch_drug_library_entry(drug_name: &str) -> Result<Option<DrugLibraryEntry>, sqlx::Error> {
+    let db = get_db();
+
+    let entry = sqlx::query_as::<_, DrugLibraryEntry>("SELECT * FROM drug_library WHERE drug_name = ?")
+        .bind(drug_name)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(entry)
+}
+
+/// Inserts the entries or updates the ones with the same `drug_code`, all or nothing.
+pub async fn upsert_drug_library(entries: &[DrugLibraryEntry]) -> Result<(), sqlx::Error> {
+    let mut tx = get_db().begin().await?;
+    let now = Local::now().naive_local();
+
+    for entry in entries {
+        sqlx::query(
+            "INSERT INTO drug_library (drug_code, drug_name, concentration, min_drip_rate, max_drip_rate, soft_min_drip_rate, soft_max_drip_rate, max_volume, tem_gear, min_temperature, max_temperature, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (drug_code) DO UPDATE SET
+                drug_name = excluded.drug_name,
+                concentration = excluded.concentration,
+                min_drip_rate = excluded.min_drip_rate,
+                max_drip_rate = excluded.max_drip_rate,
+                soft_min_drip_rate = excluded.soft_min_drip_rate,
+                soft_max_drip_rate = excluded.soft_max_drip_rate,
+                max_volume = excluded.max_volume,
+                tem_gear = excluded.tem_gear,
+                min_temperature = excluded.min_temperature,
+                max_temperature = excluded.max_temperature,
+                updated_at = excluded.updated_at"
+        )
+        .bind(&entry.drug_code)
+        .bind(&entry.drug_name)
+        .bind(&entry.concentration)
+        .bind(entry.min_drip_rate)
+        .bind(entry.max_drip_rate)
+        .bind(entry.soft_min_drip_rate)
+        .bind(entry.soft_max_drip_rate)
+        .bind(entry.max_volume)
+        .bind(entry.tem_gear)
+        .bind(entry.min_temperature)
+        .bind(entry.max_temperature)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn delete_drug_library_entry_by_id(id: i64) -> Result<bool, sqlx::Error> {
+    let db = get_db();
+
+    let result = sqlx::query("DELETE FROM drug_library WHERE id = ?")
+        .bind(id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
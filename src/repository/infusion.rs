@@ -1,77 +1,224 @@
+use chrono::{Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
-use crate::{db::get_db, mq::{publish_alarm, Alarm}};
+use crate::{config::get_config, db::get_db, mq::DeviceData};
 
-use super::Drug;
+use super::{fetch_current_drug, Patient};
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct Infusion {
-    id: Option<i64>,
-    pub name: String,
-    pub gender: String,
-    pub age: u16,
-    pub bed_no: String,
-    pub device_id: String,
-    
+    pub id: Option<i64>,
+    pub patient_no: String,
+    pub device_id: u8,
+    pub drug_id: Option<i64>,
+    pub drug_name: Option<String>,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub preset_amount: Option<u16>,
+    pub delivered_amount: Option<u16>,
+    pub final_state: Option<u8>, //1: 输液完成，2：中途停止
+    pub drops_per_ml: Option<u16>, //输液器滴系数（滴/ml）
 }
 
-impl Infusion {
-    pub fn new(name: String, gender: String, age: u16, bed_no: String, device_id: String) -> Self {
-        Self { id: None, name, gender, age, bed_no, device_id }
-    }
+#[derive(Debug, Clone, Copy)]
+pub enum InfusionFinalState {
+    Completed = 1,
+    Stopped = 2,
 }
 
+/// Remaining volume and completion time of a running infusion, derived from recent frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InfusionEstimate {
+    pub device_id: u8,
+    pub remaining_amount: u16,
+    pub drip_rate: f64, //近期平均滴速（滴/分）
+    pub drops_per_ml: u16,
+    pub remaining_secs: Option<u64>,
+    pub eta: Option<NaiveDateTime>,
+}
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct InfusionWithDetail {
-    pub id: Option<i64>,
-    pub name: String,
-    pub gender: String,
-    pub age: u16,
-    pub bed_no: String,
-    pub device_id: String,
-    pub drugs: Vec<Drug>,
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub infusion: Infusion,
+    pub name: Option<String>,
+    pub bed_no: Option<String>,
 }
 
-impl InfusionWithDetail {
-    pub fn new(infusion: Infusion, drugs: Vec<Drug>) -> Self {
-        Self { id: infusion.id, name: infusion.name, gender: infusion.gender, age: infusion.age, bed_no: infusion.bed_no, device_id: infusion.device_id, drugs }
-    }
+pub async fn fetch_active_infusion_by_device_id(device_id: u8) -> Result<Option<Infusion>, sqlx::Error> {
+    let db = get_db();
+
+    let infusion = sqlx::query_as::<_, Infusion>("SELECT * FROM infusion WHERE device_id = ? AND ended_at IS NULL ORDER BY id DESC LIMIT 1")
+        .bind(device_id)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(infusion)
 }
 
-pub async fn insert_infusion(infusion: Infusion) -> Result<(), sqlx::Error> {
-    println!("infusion inserted");
+/// Opens an infusion session for the patient bound to the device, unless one is already running.
+pub async fn start_infusion(device_data: &DeviceData) -> Result<Option<Infusion>, sqlx::Error> {
+    if let Some(infusion) = fetch_active_infusion_by_device_id(device_data.device_id).await? {
+        return Ok(Some(infusion));
+    }
 
-    let mut tx = get_db().begin().await?;
+    let db = get_db();
 
-    sqlx::query("INSERT INTO infusion (name, gender, age, bed_no, device_id) VALUES (?, ?, ?, ?, ?)")
-        .bind(infusion.name.clone())
-        .bind(infusion.gender.clone())
-        .bind(infusion.age)
-        .bind(infusion.bed_no.clone())
-        .bind(infusion.device_id.clone())
+    let patient = match sqlx::query_as::<_, Patient>("SELECT * FROM patient WHERE device_id = ? LIMIT 1")
+        .bind(device_data.device_id)
+        .fetch_optional(db.as_ref())
+        .await? {
+            Some(patient) => patient,
+            None => return Ok(None),
+        };
+
+    let drug = fetch_current_drug(&patient).await?;
+
+    let infusion = Infusion {
+        id: None,
+        patient_no: patient.patient_no,
+        device_id: device_data.device_id,
+        drug_id: drug.as_ref().and_then(|d| d.id),
+        drug_name: drug.map(|d| d.drug_name),
+        started_at: Local::now().naive_local(),
+        ended_at: None,
+        preset_amount: Some(device_data.preset_amount),
+        delivered_amount: Some(device_data.cumulative_amount),
+        final_state: None,
+        drops_per_ml: Some(get_config().infusion.drops_per_ml),
+    };
+
+    let mut tx = db.begin().await?;
+
+    let id = sqlx::query("INSERT INTO infusion (patient_no, device_id, drug_id, drug_name, started_at, preset_amount, delivered_amount, drops_per_ml) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(infusion.patient_no.clone())
+        .bind(infusion.device_id)
+        .bind(infusion.drug_id)
+        .bind(infusion.drug_name.clone())
+        .bind(infusion.started_at)
+        .bind(infusion.preset_amount)
+        .bind(infusion.delivered_amount)
+        .bind(infusion.drops_per_ml)
         .execute(&mut *tx)
-        .await?;
-
-    println!("infusion inserted");
+        .await?
+        .last_insert_rowid();
 
     sqlx::query("UPDATE device SET status = 2 WHERE device_id = ?")
-        .bind(infusion.device_id.clone())
+        .bind(infusion.device_id)
         .execute(&mut *tx)
         .await?;
-    println!("device status updated");
 
     tx.commit().await?;
 
+    Ok(Some(Infusion { id: Some(id), ..infusion }))
+}
+
+pub async fn update_infusion_progress(device_data: &DeviceData) -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query("UPDATE infusion SET preset_amount = ?, delivered_amount = ? WHERE device_id = ? AND ended_at IS NULL")
+        .bind(device_data.preset_amount)
+        .bind(device_data.cumulative_amount)
+        .bind(device_data.device_id)
+        .execute(db.as_ref())
+        .await?;
+
     Ok(())
 }
 
-pub async fn query_infusions() -> Result<Vec<Infusion>, sqlx::Error> {
+/// Calibrates the infusion set of the running session. Returns `false` when the device has none.
+pub async fn update_infusion_drops_per_ml(device_id: u8, drops_per_ml: u16) -> Result<bool, sqlx::Error> {
     let db = get_db();
 
-    let infusions = sqlx::query_as::<_, Infusion>("SELECT * FROM infusion")
+    let result = sqlx::query("UPDATE infusion SET drops_per_ml = ? WHERE device_id = ? AND ended_at IS NULL")
+        .bind(drops_per_ml)
+        .bind(device_id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Closes the running session of the device, if any, and returns it.
+pub async fn finish_infusion(device_id: u8, delivered_amount: Option<u16>, final_state: InfusionFinalState) -> Result<Option<Infusion>, sqlx::Error> {
+    let infusion = match fetch_active_infusion_by_device_id(device_id).await? {
+        Some(infusion) => infusion,
+        None => return Ok(None),
+    };
+
+    let db = get_db();
+    let ended_at = Local::now().naive_local();
+    let delivered_amount = delivered_amount.or(infusion.delivered_amount);
+
+    sqlx::query("UPDATE infusion SET ended_at = ?, delivered_amount = ?, final_state = ? WHERE id = ?")
+        .bind(ended_at)
+        .bind(delivered_amount)
+        .bind(final_state as u8)
+        .bind(infusion.id)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(Some(Infusion {
+        ended_at: Some(ended_at),
+        delivered_amount,
+        final_state: Some(final_state as u8),
+        ..infusion
+    }))
+}
+
+pub async fn query_active_infusions() -> Result<Vec<InfusionWithDetail>, sqlx::Error> {
+    let db = get_db();
+
+    let infusions = sqlx::query_as::<_, InfusionWithDetail>(
+        "SELECT i.*, p.name, p.bed_no
+        FROM infusion i
+        LEFT JOIN patient p ON p.patient_no = i.patient_no
+        WHERE i.ended_at IS NULL
+        ORDER BY i.started_at DESC"
+    )
         .fetch_all(db.as_ref())
         .await?;
 
     Ok(infusions)
 }
+
+pub async fn query_infusion_history(page: u16, page_size: u16, patient_no: Option<String>, device_id: Option<u8>) -> Result<Vec<InfusionWithDetail>, sqlx::Error> {
+    let db = get_db();
+
+    let offset = (page.max(1) as i64 - 1) * page_size as i64;
+
+    let mut query = String::from(
+        "SELECT i.*, p.name, p.bed_no
+        FROM infusion i
+        LEFT JOIN patient p ON p.patient_no = i.patient_no
+        WHERE i.ended_at IS NOT NULL"
+    );
+
+    if patient_no.is_some() {
+        query.push_str(" AND i.patient_no = ?");
+    }
+
+    if device_id.is_some() {
+        query.push_str(" AND i.device_id = ?");
+    }
+
+    query.push_str(" ORDER BY i.started_at DESC LIMIT ? OFFSET ?");
+
+    let mut query_builder = sqlx::query_as::<_, InfusionWithDetail>(&query);
+
+    if let Some(p) = patient_no {
+        query_builder = query_builder.bind(p);
+    }
+
+    if let Some(d) = device_id {
+        query_builder = query_builder.bind(d);
+    }
+
+    query_builder = query_builder.bind(page_size).bind(offset);
+
+    query_builder.fetch_all(db.as_ref()).await
+}
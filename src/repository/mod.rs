@@ -3,9 +3,23 @@ mod bed;
 mod patient;
 mod drug;
 mod infusion;
+mod command;
+mod alarm;
+mod patient_transition;
+mod telemetry;
+mod binding_attempt;
+mod drip_rate_override;
+mod drug_library;
 
 pub use device::*;
 pub use bed::*;
 pub use patient::*;
 pub use drug::*;
 pub use infusion::*;
+pub use command::*;
+pub use alarm::*;
+pub use patient_transition::*;
+pub use telemetry::*;
+pub use binding_attempt::*;
+pub use drip_rate_override::*;
+pub use drug_library::*;
@@ -1,8 +1,9 @@
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use sqlx::{prelude::FromRow};
-use crate::{db::get_db, mq::{publish_alarm, Alarm, DeviceData, DeviceStatus}};
+use crate::{db::get_db, mq::{DeviceData, DeviceStatus}};
 
-use super::Drug;
+use super::{Drug, InfusionEstimate};
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Patient {
@@ -17,7 +18,7 @@ pub struct Patient {
     pub current_drop_rate: Option<u16>,
     pub current_temperature: Option<u16>,
     pub total_drop: Option<u16>,
-    pub status: Option<u16>, //0: 未绑定，1: 已绑定（开机中）， 2：输液中， 3： 输液完成， 4： 输液暂停， 5： 待输液， 6： 输完液以解绑（关机中）
+    pub status: Option<u16>, //0: 未绑定，1: 已绑定（开机中）， 2：输液中， 3： 输液完成， 4： 输液暂停， 5： 待输液， 6： 输完液以解绑（关机中），见 PatientState
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,12 +31,13 @@ pub struct PatientDetail {
     age: u16,
     bed_no: String,
     drugs: Option<Vec<DrugDetail>>,
-    device_id: Option<u16>,
+    pub device_id: Option<u16>,
     current_drug_id: Option<String>,
     current_drop_rate: Option<u16>,
     current_temperature: Option<u16>,
     total_drop: Option<u16>,
     status: Option<u16>,
+    pub estimate: Option<InfusionEstimate>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,6 +46,10 @@ pub struct DrugDetail {
     id: Option<i64>,
     drug_name: Option<String>,
     dosage: Option<u16>,
+    drip_rate: Option<u16>,
+    seq: u16,
+    state: u8, //见 DrugState
+    delivered_amount: Option<u16>,
 }
 
 impl Patient {
@@ -58,14 +64,16 @@ impl Patient {
 pub async fn query_patient() -> Result<Vec<Patient>, sqlx::Error> {
     let db = get_db();
 
-    let patients = sqlx::query_as::<_, Patient>("SELECT * FROM patient")
+    let patients = sqlx::query_as::<_, Patient>("SELECT * FROM patient WHERE archived = 0")
         .fetch_all(db.as_ref())
         .await?;
 
     Ok(patients)
 }
 
-pub async fn insert_patients(patients: Vec<Patient>) -> Result<(), sqlx::Error> {
+/// Inserts new patients and refreshes the HIS-owned fields of existing ones, leaving
+/// the local binding and infusion progress untouched.
+pub async fn upsert_patients(patients: Vec<Patient>, synced_at: NaiveDateTime) -> Result<(), sqlx::Error> {
     if patients.is_empty() {
         return Ok(());
     }
@@ -73,12 +81,22 @@ pub async fn insert_patients(patients: Vec<Patient>) -> Result<(), sqlx::Error>
     let mut tx = get_db().begin().await?;
 
     for patient in patients {
-        sqlx::query("INSERT INTO patient (patient_no, name, gender, age, bed_no) VALUES (?, ?, ?, ?, ?)")
+        sqlx::query(
+            "INSERT INTO patient (patient_no, name, gender, age, bed_no, synced_at) VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (patient_no) DO UPDATE SET
+                name = excluded.name,
+                gender = excluded.gender,
+                age = excluded.age,
+                bed_no = excluded.bed_no,
+                synced_at = excluded.synced_at,
+                archived = 0"
+        )
         .bind(patient.patient_no.clone())
         .bind(patient.name.clone())
         .bind(patient.gender.clone())
         .bind(patient.age)
         .bind(patient.bed_no.clone())
+        .bind(synced_at)
         .execute(&mut *tx)
         .await?;
     }
@@ -88,10 +106,23 @@ pub async fn insert_patients(patients: Vec<Patient>) -> Result<(), sqlx::Error>
     Ok(())
 }
 
+/// Archives patients that were not part of the sync started at `synced_at`. Patients that still
+/// have a device bound are kept until they are unbound.
+pub async fn archive_unsynced_patients(synced_at: NaiveDateTime) -> Result<u64, sqlx::Error> {
+    let db = get_db();
+
+    let result = sqlx::query("UPDATE patient SET archived = 1 WHERE archived = 0 AND device_id IS NULL AND (synced_at IS NULL OR synced_at < ?)")
+        .bind(synced_at)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
 pub async fn fetch_patient_by_bed_no(bed_no: String) -> Result<Option<Patient>, sqlx::Error> {
     let db = get_db();
 
-    let patient = sqlx::query_as::<_, Patient>("SELECT * FROM patient WHERE bed_no = ?")
+    let patient = sqlx::query_as::<_, Patient>("SELECT * FROM patient WHERE bed_no = ? AND archived = 0 ORDER BY id DESC LIMIT 1")
         .bind(bed_no)
         .fetch_optional(db.as_ref())
         .await?;
@@ -99,14 +130,36 @@ pub async fn fetch_patient_by_bed_no(bed_no: String) -> Result<Option<Patient>,
     Ok(patient)
 }
 
+pub async fn fetch_patient_by_patient_no(patient_no: String) -> Result<Option<Patient>, sqlx::Error> {
+    let db = get_db();
+
+    let patient = sqlx::query_as::<_, Patient>("SELECT * FROM patient WHERE patient_no = ? AND archived = 0 LIMIT 1")
+        .bind(patient_no)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(patient)
+}
+
+pub async fn fetch_patient_by_device_id(device_id: u8) -> Result<Option<Patient>, sqlx::Error> {
+    let db = get_db();
+
+    let patient = sqlx::query_as::<_, Patient>("SELECT * FROM patient WHERE device_id = ? LIMIT 1")
+        .bind(device_id)
+        .fetch_optional(db.as_ref())
+        .await?;
+
+    Ok(patient)
+}
+
+/// Copies the latest readings of the device onto its patient. The status is left to `patient_state`.
 pub async fn update_patient_by_device_id(device_data: DeviceData) -> Result<(), sqlx::Error> {
     let db = get_db();
 
-    sqlx::query("UPDATE patient SET current_drop_rate = ?, current_temperature = ?, total_drop = ?, status = ? WHERE device_id = ?")
+    sqlx::query("UPDATE patient SET current_drop_rate = ?, current_temperature = ?, total_drop = ? WHERE device_id = ?")
     .bind(device_data.drip_value)
     .bind(device_data.tem_value)
     .bind(device_data.cumulative_amount)
-    .bind(device_data.status)
     .bind(device_data.device_id)
     .execute(db.as_ref())
     .await?;
@@ -117,7 +170,7 @@ pub async fn update_patient_by_device_id(device_data: DeviceData) -> Result<(),
 pub async fn update_patient_device_id(patient_no: String, device_id: u8) -> Result<(), sqlx::Error> {
     let db = get_db();
 
-    sqlx::query("UPDATE patient SET device_id = ?, status = 1 WHERE patient_no = ?")
+    sqlx::query("UPDATE patient SET device_id = ? WHERE patient_no = ?")
     .bind(device_id)
     .bind(patient_no)
     .execute(db.as_ref())
@@ -146,7 +199,7 @@ pub async fn fetch_all_patient_page(page: u16, page_size: u16, status: Option<u1
             total_drop,
             status
         FROM patient
-        WHERE 1=1"
+        WHERE archived = 0"
     );
 
     if status.is_some() {
@@ -176,11 +229,30 @@ pub async fn fetch_all_patient_page(page: u16, page_size: u16, status: Option<u1
     let mut result = Vec::<PatientDetail>::new();
 
     for patient in patient_details {
-        let drugs = sqlx::query_as::<_, Drug>("SELECT * FROM drug WHERE patient_no = ?")
+        let drugs = sqlx::query_as::<_, Drug>("SELECT * FROM drug WHERE patient_no = ? ORDER BY seq, id")
         .bind(patient.patient_no.clone())
         .fetch_all(db.as_ref())
         .await?;
 
+        let mut plan = Vec::with_capacity(drugs.len());
+        for drug in drugs {
+            // Volume of the latest bag of this drug, the running one included.
+            let delivered_amount: Option<Option<u16>> = sqlx::query_scalar("SELECT delivered_amount FROM infusion WHERE drug_id = ? ORDER BY id DESC LIMIT 1")
+                .bind(drug.id)
+                .fetch_optional(db.as_ref())
+                .await?;
+
+            plan.push(DrugDetail {
+                id: drug.id,
+                drug_name: Some(drug.drug_name),
+                dosage: Some(drug.dosage),
+                drip_rate: Some(drug.drip_rate),
+                seq: drug.seq,
+                state: drug.state,
+                delivered_amount: delivered_amount.flatten(),
+            });
+        }
+
         result.push(PatientDetail {
             id: patient.id,
             patient_no: patient.patient_no,
@@ -188,17 +260,14 @@ pub async fn fetch_all_patient_page(page: u16, page_size: u16, status: Option<u1
             gender: patient.gender,
             age: patient.age,
             bed_no: patient.bed_no,
-            drugs: Some(drugs.into_iter().map(|drug| DrugDetail {
-                id: drug.id,
-                drug_name: Some(drug.drug_name),
-                dosage: Some(drug.dosage),
-            }).collect()),
+            drugs: Some(plan),
             status: patient.status,
             total_drop: patient.total_drop,
             device_id: patient.device_id,
             current_drug_id: patient.current_drug_id.clone(),
             current_drop_rate: patient.current_drop_rate,
             current_temperature: patient.current_temperature,
+            estimate: None,
         });
     }
 
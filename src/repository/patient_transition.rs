@@ -0,0 +1,165 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use crate::db::get_db;
+
+use super::{Infusion, InfusionFinalState};
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct PatientTransition {
+    pub id: i64,
+    pub patient_no: String,
+    pub from_state: Option<u16>,
+    pub to_state: u16,
+    pub cause: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Moves the patient from `from` to `to` and records the transition in one transaction.
+/// Returns `None` when the status no longer is `from`, i.e. another writer got there first.
+pub async fn apply_patient_transition(patient_no: &str, from: Option<u16>, to: u16, cause: &str) -> Result<Option<PatientTransition>, sqlx::Error> {
+    let mut tx = get_db().begin().await?;
+    let created_at = Local::now().naive_local();
+
+    let result = sqlx::query("UPDATE patient SET status = ? WHERE patient_no = ? AND status IS ?")
+        .bind(to)
+        .bind(patient_no)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let id = sqlx::query("INSERT INTO patient_transition (patient_no, from_state, to_state, cause, created_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(patient_no)
+        .bind(from)
+        .bind(to)
+        .bind(cause)
+        .bind(created_at)
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+
+    tx.commit().await?;
+
+    Ok(Some(PatientTransition {
+        id,
+        patient_no: patient_no.to_string(),
+        from_state: from,
+        to_state: to,
+        cause: cause.to_string(),
+        created_at,
+    }))
+}
+
+/// Result of `apply_unbinding`: the recorded moves and the infusion session it closed, if any.
+#[derive(Debug)]
+pub struct Unbinding {
+    pub transitions: Vec<PatientTransition>,
+    pub infusion: Option<Infusion>,
+}
+
+/// Detaches the device from the patient in one transaction: closes the open infusion session,
+/// moves the patient from `from` through `steps` (target state and cause each), clears
+/// `patient.device_id` and resets `device.do_bind`. Returns `None` and writes nothing when the
+/// status no longer is `from`.
+pub async fn apply_unbinding(
+    device_id: u8,
+    patient_no: &str,
+    from: Option<u16>,
+    steps: &[(u16, &str)],
+    final_state: InfusionFinalState,
+    delivered_amount: Option<u16>,
+) -> Result<Option<Unbinding>, sqlx::Error> {
+    let mut tx = get_db().begin().await?;
+    let now = Local::now().naive_local();
+
+    let mut transitions = Vec::with_capacity(steps.len());
+    let mut current = from;
+    for &(to, cause) in steps {
+        let result = sqlx::query("UPDATE patient SET status = ? WHERE patient_no = ? AND status IS ?")
+            .bind(to)
+            .bind(patient_no)
+            .bind(current)
+            .execute(&mut *tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let id = sqlx::query("INSERT INTO patient_transition (patient_no, from_state, to_state, cause, created_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(patient_no)
+            .bind(current)
+            .bind(to)
+            .bind(cause)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+
+        transitions.push(PatientTransition {
+            id,
+            patient_no: patient_no.to_string(),
+            from_state: current,
+            to_state: to,
+            cause: cause.to_string(),
+            created_at: now,
+        });
+        current = Some(to);
+    }
+
+    let infusion = sqlx::query_as::<_, Infusion>("SELECT * FROM infusion WHERE device_id = ? AND ended_at IS NULL ORDER BY id DESC LIMIT 1")
+        .bind(device_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let infusion = match infusion {
+        Some(infusion) => {
+            let delivered_amount = delivered_amount.or(infusion.delivered_amount);
+            sqlx::query("UPDATE infusion SET ended_at = ?, delivered_amount = ?, final_state = ? WHERE id = ?")
+                .bind(now)
+                .bind(delivered_amount)
+                .bind(final_state as u8)
+                .bind(infusion.id)
+                .execute(&mut *tx)
+                .await?;
+
+            Some(Infusion {
+                ended_at: Some(now),
+                delivered_amount,
+                final_state: Some(final_state as u8),
+                ..infusion
+            })
+        }
+        None => None,
+    };
+
+    sqlx::query("UPDATE patient SET device_id = NULL WHERE patient_no = ?")
+        .bind(patient_no)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("UPDATE device SET do_bind = 0 WHERE device_id = ?")
+        .bind(device_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(Unbinding { transitions, infusion }))
+}
+
+pub async fn query_patient_transitions(patient_no: String) -> Result<Vec<PatientTransition>, sqlx::Error> {
+    let db = get_db();
+
+    let transitions = sqlx::query_as::<_, PatientTransition>("SELECT * FROM patient_transition WHERE patient_no = ? ORDER BY id DESC")
+        .bind(patient_no)
+        .fetch_all(db.as_ref())
+        .await?;
+
+    Ok(transitions)
+}
@@ -0,0 +1,140 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use crate::{db::get_db, mq::DeviceData};
+
+/// One `device_data` frame, or the aggregate of `samples` frames once downsampled.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct TelemetryPoint {
+    pub device_id: u8,
+    pub patient_no: Option<String>,
+    pub drip_value: u8,
+    pub preset_amount: u16,
+    pub cumulative_amount: u16,
+    pub tem_value: u8,
+    pub tem_gear_value: u8,
+    pub status: u8,
+    pub power_state: u8,
+    pub samples: u32,
+    pub recorded_at: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub struct TelemetryQuery {
+    pub device_id: Option<u8>,
+    pub patient_no: Option<String>,
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub bucket_secs: Option<u32>,
+}
+
+/// Averages rates and keeps the highest volumes of every bucket, shared by downsampling and charting.
+const AGGREGATE_COLUMNS: &str = "device_id,
+    MAX(patient_no) AS patient_no,
+    CAST(ROUND(AVG(drip_value)) AS INTEGER) AS drip_value,
+    MAX(preset_amount) AS preset_amount,
+    MAX(cumulative_amount) AS cumulative_amount,
+    CAST(ROUND(AVG(tem_value)) AS INTEGER) AS tem_value,
+    MAX(tem_gear_value) AS tem_gear_value,
+    MAX(status) AS status,
+    MIN(power_state) AS power_state,
+    SUM(samples) AS samples,
+    MIN(recorded_at) AS recorded_at";
+
+pub async fn insert_telemetry(device_data: &DeviceData, patient_no: Option<String>) -> Result<(), sqlx::Error> {
+    let db = get_db();
+
+    sqlx::query("INSERT INTO telemetry (device_id, patient_no, drip_value, preset_amount, cumulative_amount, tem_value, tem_gear_value, status, power_state, samples, downsampled, recorded_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1, 0, ?)")
+        .bind(device_data.device_id)
+        .bind(patient_no)
+        .bind(device_data.drip_value)
+        .bind(device_data.preset_amount)
+        .bind(device_data.cumulative_amount)
+        .bind(device_data.tem_value)
+        .bind(device_data.tem_gear_value)
+        .bind(device_data.status)
+        .bind(device_data.power_state)
+        .bind(Local::now().naive_local())
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(())
+}
+
+pub async fn query_telemetry(filter: TelemetryQuery) -> Result<Vec<TelemetryPoint>, sqlx::Error> {
+    let db = get_db();
+
+    let mut query = match filter.bucket_secs {
+        Some(_) => format!("SELECT {} FROM telemetry WHERE recorded_at >= ? AND recorded_at <= ?", AGGREGATE_COLUMNS),
+        None => String::from("SELECT * FROM telemetry WHERE recorded_at >= ? AND recorded_at <= ?"),
+    };
+
+    if filter.device_id.is_some() {
+        query.push_str(" AND device_id = ?");
+    }
+
+    if filter.patient_no.is_some() {
+        query.push_str(" AND patient_no = ?");
+    }
+
+    if filter.bucket_secs.is_some() {
+        query.push_str(" GROUP BY device_id, CAST(strftime('%s', recorded_at) AS INTEGER) / ?");
+    }
+
+    query.push_str(" ORDER BY recorded_at");
+
+    let mut query_builder = sqlx::query_as::<_, TelemetryPoint>(&query)
+        .bind(filter.from)
+        .bind(filter.to);
+
+    if let Some(d) = filter.device_id {
+        query_builder = query_builder.bind(d);
+    }
+
+    if let Some(p) = filter.patient_no {
+        query_builder = query_builder.bind(p);
+    }
+
+    if let Some(b) = filter.bucket_secs {
+        query_builder = query_builder.bind(b.max(1));
+    }
+
+    query_builder.fetch_all(db.as_ref()).await
+}
+
+/// Replaces raw frames recorded before `before` with one aggregated row per device and bucket.
+pub async fn downsample_telemetry(before: NaiveDateTime, bucket_secs: u32) -> Result<u64, sqlx::Error> {
+    let mut tx = get_db().begin().await?;
+
+    sqlx::query(&format!(
+        "INSERT INTO telemetry (device_id, patient_no, drip_value, preset_amount, cumulative_amount, tem_value, tem_gear_value, status, power_state, samples, recorded_at, downsampled)
+        SELECT {}, 1 FROM telemetry WHERE downsampled = 0 AND recorded_at < ?
+        GROUP BY device_id, CAST(strftime('%s', recorded_at) AS INTEGER) / ?",
+        AGGREGATE_COLUMNS
+    ))
+        .bind(before)
+        .bind(bucket_secs.max(1))
+        .execute(&mut *tx)
+        .await?;
+
+    let result = sqlx::query("DELETE FROM telemetry WHERE downsampled = 0 AND recorded_at < ?")
+        .bind(before)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_telemetry_before(before: NaiveDateTime) -> Result<u64, sqlx::Error> {
+    let db = get_db();
+
+    let result = sqlx::query("DELETE FROM telemetry WHERE recorded_at < ?")
+        .bind(before)
+        .execute(db.as_ref())
+        .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,359 @@
+use std::fmt;
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::command::{self, CommandReceipt};
+use crate::config::{get_config, Config};
+use crate::protocol::DeviceCommand;
+use crate::repository::{
+    attach_drip_rate_override_command, fetch_current_drug, fetch_drug_library_entry, fetch_patient_by_device_id, insert_drip_rate_override, Drug, DrugLibraryEntry, NewDripRateOverride, Patient,
+};
+
+/// What a device may be set to. `min_rate`/`max_rate` follow the prescription (or the soft limits
+/// of the drug library) and can be overridden with a reason, `hard_min`/`hard_max` and
+/// `max_volume` never can.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InfusionLimits {
+    pub device_id: u8,
+    pub patient_no: Option<String>,
+    pub drug_id: Option<i64>,
+    pub drug_name: Option<String>,
+    pub drug_code: Option<String>,
+    pub prescribed_rate: Option<u16>,
+    pub min_rate: u16,
+    pub max_rate: u16,
+    pub hard_min: u16,
+    pub hard_max: u16,
+    pub max_volume: Option<u16>,
+    pub tem_gear: Option<u8>,
+    pub max_tem_gear: u8,
+    pub min_temperature: Option<u8>,
+    pub max_temperature: Option<u8>,
+}
+
+impl InfusionLimits {
+    pub fn within_hard_limits(&self, drip_rate: u8) -> bool {
+        (self.hard_min..=self.hard_max).contains(&(drip_rate as u16))
+    }
+
+    pub fn within_max_volume(&self, amount: u16) -> bool {
+        self.max_volume.is_none_or(|max| amount <= max)
+    }
+
+    /// A drug that needs a specific warming gear only accepts that gear.
+    pub fn within_tem_gear(&self, tem_gear: u8) -> bool {
+        tem_gear <= self.max_tem_gear && self.tem_gear.is_none_or(|required| tem_gear == required)
+    }
+
+    fn within_prescription(&self, drip_rate: u8) -> bool {
+        (self.min_rate..=self.max_rate).contains(&(drip_rate as u16))
+    }
+}
+
+#[derive(Debug)]
+pub enum SafetyError {
+    HardLimit { drip_rate: u8, limits: InfusionLimits },
+    OutsidePrescription { drip_rate: u8, limits: InfusionLimits },
+    VolumeLimit { preset_amount: u16, limits: InfusionLimits },
+    TemGearLimit { tem_gear: u8, limits: InfusionLimits },
+    TemGearUnavailable,
+    Command(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for SafetyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafetyError::HardLimit { drip_rate, limits } =>
+                write!(f, "drip rate {} is outside the hard limits {}-{}", drip_rate, limits.hard_min, limits.hard_max),
+            SafetyError::OutsidePrescription { drip_rate, limits } =>
+                write!(f, "drip rate {} is outside the allowed range {}-{}, an override reason is required", drip_rate, limits.min_rate, limits.max_rate),
+            SafetyError::VolumeLimit { preset_amount, limits } =>
+                write!(f, "preset amount {} exceeds the maximum volume {} of the drug", preset_amount, limits.max_volume.unwrap_or_default()),
+            SafetyError::TemGearLimit { tem_gear, limits } => match limits.tem_gear {
+                Some(required) => write!(f, "temperature gear {} does not match gear {} required by the drug", tem_gear, required),
+                None => write!(f, "temperature gear {} is outside 0-{}", tem_gear, limits.max_tem_gear),
+            },
+            SafetyError::TemGearUnavailable => write!(f, "temperature gear register is not configured (device.tem_gear_register)"),
+            SafetyError::Command(e) => write!(f, "{}", e),
+            SafetyError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SafetyError {}
+
+impl From<sqlx::Error> for SafetyError {
+    fn from(e: sqlx::Error) -> Self {
+        SafetyError::Database(e)
+    }
+}
+
+impl SafetyError {
+    pub fn limits(&self) -> Option<&InfusionLimits> {
+        match self {
+            SafetyError::HardLimit { limits, .. }
+            | SafetyError::OutsidePrescription { limits, .. }
+            | SafetyError::VolumeLimit { limits, .. }
+            | SafetyError::TemGearLimit { limits, .. } => Some(limits),
+            _ => None,
+        }
+    }
+}
+
+pub async fn infusion_limits(device_id: u8) -> Result<InfusionLimits, sqlx::Error> {
+    let patient = fetch_patient_by_device_id(device_id).await?;
+    limits_for(device_id, patient.as_ref()).await
+}
+
+/// Limits for the current drug of the patient on the device. The hard limits of the config are
+/// narrowed by the drug library; the range that needs no override is the prescribed rate
+/// ±`drip_rate_deviation`, or the library soft limits when nothing is prescribed.
+pub async fn limits_for(device_id: u8, patient: Option<&Patient>) -> Result<InfusionLimits, sqlx::Error> {
+    let drug = match patient {
+        Some(patient) => fetch_current_drug(patient).await?,
+        None => None,
+    };
+    limits_for_drug(device_id, patient, drug.as_ref()).await
+}
+
+/// Limits for a given drug of the patient, e.g. the next bag before it becomes current.
+pub async fn limits_for_drug(device_id: u8, patient: Option<&Patient>, drug: Option<&Drug>) -> Result<InfusionLimits, sqlx::Error> {
+    let entry = match drug {
+        Some(drug) => fetch_drug_library_entry(&drug.drug_name).await?,
+        None => None,
+    };
+    Ok(compute_limits(get_config(), device_id, patient, drug, entry))
+}
+
+fn compute_limits(config: &Config, device_id: u8, patient: Option<&Patient>, drug: Option<&Drug>, entry: Option<DrugLibraryEntry>) -> InfusionLimits {
+    let safety = &config.safety;
+
+    let hard_min = entry.as_ref().and_then(|e| e.min_drip_rate).unwrap_or_default().max(safety.min_drip_rate as u16);
+    let hard_max = entry.as_ref().and_then(|e| e.max_drip_rate).unwrap_or(u16::MAX).min(safety.max_drip_rate as u16).max(hard_min);
+
+    let (min_rate, max_rate) = match (drug.map(|d| d.drip_rate as f64), &entry) {
+        (Some(prescribed), _) => (
+            (prescribed * (1.0 - safety.drip_rate_deviation)).floor() as u16,
+            (prescribed * (1.0 + safety.drip_rate_deviation)).ceil() as u16,
+        ),
+        (None, Some(entry)) => (entry.soft_min_drip_rate.unwrap_or(hard_min), entry.soft_max_drip_rate.unwrap_or(hard_max)),
+        (None, None) => (hard_min, hard_max),
+    };
+
+    InfusionLimits {
+        device_id,
+        patient_no: patient.map(|p| p.patient_no.clone()),
+        drug_id: drug.and_then(|d| d.id),
+        prescribed_rate: drug.map(|d| d.drip_rate),
+        drug_name: drug.map(|d| d.drug_name.clone()),
+        drug_code: entry.as_ref().map(|e| e.drug_code.clone()),
+        min_rate: min_rate.clamp(hard_min, hard_max),
+        max_rate: max_rate.clamp(hard_min, hard_max),
+        hard_min,
+        hard_max,
+        max_volume: entry.as_ref().and_then(|e| e.max_volume),
+        tem_gear: entry.as_ref().and_then(|e| e.tem_gear),
+        max_tem_gear: config.device.max_tem_gear,
+        min_temperature: entry.as_ref().and_then(|e| e.min_temperature).or(config.alarm.min_temperature),
+        max_temperature: entry.and_then(|e| e.max_temperature).or(config.alarm.max_temperature),
+    }
+}
+
+/// Checks the requested rate against the prescription before sending it. A rate outside the
+/// prescribed range goes through only with an override reason, which is recorded together with
+/// the command; the hard limits cannot be overridden.
+pub async fn set_drip_rate(device_id: u8, drip_rate: u8, override_reason: Option<String>) -> Result<CommandReceipt, SafetyError> {
+    let limits = infusion_limits(device_id).await?;
+    dispatch_drip_rate(limits, drip_rate, override_reason).await
+}
+
+/// Rejects a preset amount above the maximum volume the drug library allows for the current drug.
+pub async fn set_preset_amount(device_id: u8, preset_amount: u16) -> Result<CommandReceipt, SafetyError> {
+    let limits = infusion_limits(device_id).await?;
+    dispatch_preset_amount(limits, preset_amount).await
+}
+
+/// Returns the override reason to record, if the rate needs one.
+pub fn check_drip_rate(limits: &InfusionLimits, drip_rate: u8, override_reason: Option<String>) -> Result<Option<String>, SafetyError> {
+    if !limits.within_hard_limits(drip_rate) {
+        return Err(SafetyError::HardLimit { drip_rate, limits: limits.clone() });
+    }
+
+    match override_reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty()) {
+        _ if limits.within_prescription(drip_rate) => Ok(None),
+        Some(reason) => Ok(Some(reason)),
+        None => Err(SafetyError::OutsidePrescription { drip_rate, limits: limits.clone() }),
+    }
+}
+
+pub fn check_preset_amount(limits: &InfusionLimits, preset_amount: u16) -> Result<(), SafetyError> {
+    if !limits.within_max_volume(preset_amount) {
+        return Err(SafetyError::VolumeLimit { preset_amount, limits: limits.clone() });
+    }
+    Ok(())
+}
+
+/// Sends the rate after `check_drip_rate` against the given limits.
+pub async fn dispatch_drip_rate(limits: InfusionLimits, drip_rate: u8, override_reason: Option<String>) -> Result<CommandReceipt, SafetyError> {
+    let device_id = limits.device_id;
+    let override_reason = check_drip_rate(&limits, drip_rate, override_reason)?;
+
+    // The audit row must exist before an out-of-range rate reaches the pump.
+    let override_id = match override_reason {
+        Some(reason) => {
+            warn!("drip rate {} outside {}-{} requested for device {}: {}", drip_rate, limits.min_rate, limits.max_rate, device_id, reason);
+
+            let record = NewDripRateOverride {
+                device_id,
+                patient_no: limits.patient_no,
+                drug_id: limits.drug_id,
+                drug_name: limits.drug_name,
+                prescribed_rate: limits.prescribed_rate,
+                min_rate: limits.min_rate,
+                max_rate: limits.max_rate,
+                requested_rate: drip_rate,
+                reason,
+            };
+            Some(insert_drip_rate_override(record).await?)
+        }
+        None => None,
+    };
+
+    let receipt = command::dispatch(DeviceCommand::SetDripRate { device_id, drip_rate })
+        .await
+        .map_err(SafetyError::Command)?;
+
+    if let Some(id) = override_id {
+        if let Err(e) = attach_drip_rate_override_command(id, receipt.command_id).await {
+            error!("attach command {} to drip rate override {} failed: {}", receipt.command_id, id, e);
+        }
+    }
+
+    Ok(receipt)
+}
+
+/// Sends the preset amount after `check_preset_amount` against the given limits.
+pub async fn dispatch_preset_amount(limits: InfusionLimits, preset_amount: u16) -> Result<CommandReceipt, SafetyError> {
+    check_preset_amount(&limits, preset_amount)?;
+
+    command::dispatch(DeviceCommand::ModifyPresetAmount { device_id: limits.device_id, preset_amount })
+        .await
+        .map_err(SafetyError::Command)
+}
+
+/// Rejects a warming gear outside the device range or other than the one the drug library
+/// requires for the current drug.
+pub async fn set_tem_gear(device_id: u8, tem_gear: u8) -> Result<CommandReceipt, SafetyError> {
+    let Some(register) = get_config().device.tem_gear_register else {
+        return Err(SafetyError::TemGearUnavailable);
+    };
+
+    let limits = infusion_limits(device_id).await?;
+    if !limits.within_tem_gear(tem_gear) {
+        return Err(SafetyError::TemGearLimit { tem_gear, limits });
+    }
+
+    command::dispatch(DeviceCommand::SetTemGear { device_id, register, tem_gear })
+        .await
+        .map_err(SafetyError::Command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(min: Option<u16>, max: Option<u16>, soft_min: Option<u16>, soft_max: Option<u16>) -> DrugLibraryEntry {
+        DrugLibraryEntry {
+            id: None,
+            drug_code: "NS500".to_string(),
+            drug_name: "0.9%氯化钠注射液".to_string(),
+            concentration: None,
+            min_drip_rate: min,
+            max_drip_rate: max,
+            soft_min_drip_rate: soft_min,
+            soft_max_drip_rate: soft_max,
+            max_volume: Some(500),
+            tem_gear: None,
+            min_temperature: None,
+            max_temperature: None,
+            updated_at: None,
+        }
+    }
+
+    /// Default config: ±20% around the prescription, hard limits 1-150.
+    fn limits(drip_rate: Option<u16>, entry: Option<DrugLibraryEntry>) -> InfusionLimits {
+        let drug = drip_rate.map(|rate| Drug::new("0.9%氯化钠注射液".to_string(), 500, rate));
+        compute_limits(&Config::default(), 5, None, drug.as_ref(), entry)
+    }
+
+    #[test]
+    fn prescription_range_is_the_deviation_around_the_prescribed_rate() {
+        let limits = limits(Some(42), None);
+
+        assert_eq!((limits.min_rate, limits.max_rate), (33, 51));
+        assert_eq!((limits.hard_min, limits.hard_max), (1, 150));
+    }
+
+    #[test]
+    fn library_hard_limits_narrow_the_config_and_clamp_the_prescription_range() {
+        let limits = limits(Some(42), Some(entry(Some(40), Some(45), None, None)));
+
+        assert_eq!((limits.hard_min, limits.hard_max), (40, 45));
+        assert_eq!((limits.min_rate, limits.max_rate), (40, 45));
+    }
+
+    #[test]
+    fn library_hard_limits_never_widen_the_config() {
+        let limits = limits(Some(100), Some(entry(Some(0), Some(400), None, None)));
+
+        assert_eq!((limits.hard_min, limits.hard_max), (1, 150));
+        assert_eq!((limits.min_rate, limits.max_rate), (80, 120));
+    }
+
+    #[test]
+    fn soft_limits_apply_only_without_a_prescription() {
+        let library = entry(Some(20), Some(100), Some(30), Some(60));
+
+        let unprescribed = limits(None, Some(library.clone()));
+        assert_eq!((unprescribed.min_rate, unprescribed.max_rate), (30, 60));
+
+        let prescribed = limits(Some(80), Some(library));
+        assert_eq!((prescribed.min_rate, prescribed.max_rate), (64, 96));
+    }
+
+    #[test]
+    fn rate_inside_the_prescription_needs_no_override() {
+        let limits = limits(Some(42), None);
+
+        assert!(matches!(check_drip_rate(&limits, 42, None), Ok(None)));
+        assert!(matches!(check_drip_rate(&limits, 51, Some("doctor asked".to_string())), Ok(None)));
+    }
+
+    #[test]
+    fn rate_outside_the_prescription_needs_a_reason() {
+        let limits = limits(Some(42), None);
+
+        assert!(matches!(check_drip_rate(&limits, 60, None), Err(SafetyError::OutsidePrescription { drip_rate: 60, .. })));
+        assert!(matches!(check_drip_rate(&limits, 60, Some("  ".to_string())), Err(SafetyError::OutsidePrescription { .. })));
+        assert_eq!(check_drip_rate(&limits, 60, Some(" doctor asked ".to_string())).unwrap(), Some("doctor asked".to_string()));
+    }
+
+    #[test]
+    fn hard_limits_cannot_be_overridden() {
+        let limits = limits(Some(30), Some(entry(None, Some(50), None, None)));
+
+        assert!(matches!(check_drip_rate(&limits, 50, Some("doctor asked".to_string())), Ok(Some(_))));
+        assert!(matches!(check_drip_rate(&limits, 51, Some("doctor asked".to_string())), Err(SafetyError::HardLimit { drip_rate: 51, .. })));
+        assert!(matches!(check_drip_rate(&limits, 0, Some("doctor asked".to_string())), Err(SafetyError::HardLimit { drip_rate: 0, .. })));
+    }
+
+    #[test]
+    fn preset_amount_is_capped_by_the_library_volume() {
+        let limits = limits(Some(42), Some(entry(None, None, None, None)));
+
+        assert!(check_preset_amount(&limits, 500).is_ok());
+        assert!(matches!(check_preset_amount(&limits, 501), Err(SafetyError::VolumeLimit { preset_amount: 501, .. })));
+    }
+}
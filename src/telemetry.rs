@@ -0,0 +1,34 @@
+use std::time::Duration;
+use chrono::Local;
+use tracing::{error, info};
+
+use crate::config::get_config;
+use crate::repository::{delete_telemetry_before, downsample_telemetry};
+
+/// Periodically compacts old raw frames and drops telemetry past the retention period.
+pub async fn run_maintenance() {
+    let telemetry_config = &get_config().telemetry;
+    let mut interval = tokio::time::interval(Duration::from_secs(telemetry_config.maintenance_interval_secs.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        let now = Local::now().naive_local();
+
+        if let Some(raw_before) = chrono::Duration::try_hours(telemetry_config.raw_retention_hours as i64).map(|d| now - d) {
+            match downsample_telemetry(raw_before, telemetry_config.downsample_bucket_secs).await {
+                Ok(count) if count > 0 => info!("{} telemetry frames downsampled", count),
+                Ok(_) => {},
+                Err(e) => error!("downsample telemetry failed: {}", e),
+            }
+        }
+
+        if let Some(expired_before) = chrono::Duration::try_days(telemetry_config.retention_days as i64).map(|d| now - d) {
+            match delete_telemetry_before(expired_before).await {
+                Ok(count) if count > 0 => info!("{} expired telemetry rows deleted", count),
+                Ok(_) => {},
+                Err(e) => error!("delete expired telemetry failed: {}", e),
+            }
+        }
+    }
+}
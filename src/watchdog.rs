@@ -0,0 +1,57 @@
+use std::time::Duration;
+use chrono::Local;
+use tracing::{error, warn};
+
+use crate::alarm;
+use crate::config::get_config;
+use crate::repository::{fetch_active_infusion_by_device_id, fetch_patient_by_device_id, mark_silent_devices_offline, AlarmType};
+
+/// Marks devices offline once they stay silent longer than `device.offline_timeout_secs`.
+pub async fn run() {
+    let device_config = &get_config().device;
+    let mut interval = tokio::time::interval(Duration::from_secs(device_config.watchdog_interval_secs.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        let Some(timeout) = chrono::Duration::try_seconds(device_config.offline_timeout_secs as i64) else {
+            return;
+        };
+
+        let devices = match mark_silent_devices_offline(Local::now().naive_local() - timeout).await {
+            Ok(devices) => devices,
+            Err(e) => {
+                error!("mark silent devices offline failed: {}", e);
+                continue;
+            }
+        };
+
+        for device in devices {
+            warn!("device {} went offline, last seen at {:?}", device.device_id, device.last_seen_at);
+            on_offline(device.device_id).await;
+        }
+    }
+}
+
+/// Only an interrupted infusion needs a nurse; an idle device going quiet is just switched off.
+async fn on_offline(device_id: u8) {
+    match fetch_active_infusion_by_device_id(device_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return,
+        Err(e) => {
+            error!("fetch active infusion failed: {}", e);
+            return;
+        }
+    }
+
+    let patient = match fetch_patient_by_device_id(device_id).await {
+        Ok(patient) => patient,
+        Err(e) => {
+            error!("fetch patient by device id failed: {}", e);
+            None
+        }
+    };
+
+    let message = format!("no data for more than {} seconds", get_config().device.offline_timeout_secs);
+    alarm::raise(AlarmType::DeviceOffline, device_id, patient.as_ref(), Some(message)).await;
+}